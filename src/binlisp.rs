@@ -0,0 +1,1793 @@
+use crate::env::{self, Executable, ExecutionEnv, Params};
+use crate::error::{unwind, ErrorKind, LispError};
+use crate::expand;
+use crate::liblisp::{self, Literal, LispPrimitive};
+use crate::num::Number;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+//Opcodes of the stack machine. Each is one byte followed by its operand,
+//a u8 argument count, a u16 table index or a u32 code offset, little endian
+const OP_CONST: u8 = 0;
+const OP_NIL: u8 = 1;
+const OP_GET_LOCAL: u8 = 2;
+//Pops into a slot as a new binding, leaving any closure that
+//captured the old one with the value it had
+const OP_STORE_LOCAL: u8 = 3;
+//Copies the top of the stack into a slot, for set!
+const OP_SET_LOCAL: u8 = 4;
+//Upvalues share a cell with the slot they were captured from, so an
+//assignment through either is seen by both
+const OP_GET_UPVALUE: u8 = 5;
+//Globals are named by a constant and looked up in the closure's environment
+const OP_GET_GLOBAL: u8 = 6;
+const OP_SET_GLOBAL: u8 = 7;
+const OP_POP: u8 = 8;
+const OP_DUP: u8 = 9;
+const OP_JUMP: u8 = 10;
+//Conditional jumps pop the value they test
+const OP_JUMP_IF_FALSE: u8 = 11;
+const OP_JUMP_IF_TRUE: u8 = 12;
+const OP_CALL: u8 = 13;
+const OP_TAIL_CALL: u8 = 14;
+const OP_RETURN: u8 = 15;
+const OP_CLOSURE: u8 = 16;
+//Pops a value into a new global binding and pushes its name
+const OP_DEFINE_GLOBAL: u8 = 17;
+//Tree-walks a constant form, for top level forms the compiler can't handle
+const OP_EVAL: u8 = 18;
+//Pop the given number of elements into a new collection,
+//for a map keys and values alternate
+const OP_VECTOR: u8 = 19;
+const OP_MAP: u8 = 20;
+const OP_SET: u8 = 21;
+//Globals named by a syntax-rules template, looked up in the outermost
+//scope however the scopes of the closure bind the same name
+const OP_GET_ROOT: u8 = 22;
+const OP_SET_ROOT: u8 = 23;
+//Copies the top of the stack into an upvalue, for set!
+const OP_SET_UPVALUE: u8 = 24;
+
+//Names and operand widths in bytes, indexed by opcode
+const OPCODES: [(&str, usize); 25] = [
+	("const", 2),
+	("nil", 0),
+	("get-local", 2),
+	("store-local", 2),
+	("set-local", 2),
+	("get-upvalue", 2),
+	("get-global", 2),
+	("set-global", 2),
+	("pop", 0),
+	("dup", 0),
+	("jump", 4),
+	("jump-if-false", 4),
+	("jump-if-true", 4),
+	("call", 1),
+	("tail-call", 1),
+	("return", 0),
+	("closure", 2),
+	("define-global", 2),
+	("eval", 2),
+	("vector", 2),
+	("map", 2),
+	("set", 2),
+	("get-root", 2),
+	("set-root", 2),
+	("set-upvalue", 2),
+];
+
+//A compiled lambda, shared by every closure made from it
+#[derive(Debug)]
+pub struct Function {
+	//Names the function in arity errors
+	pub name: String,
+	//Required, then optional, then rest parameters
+	pub params: Vec<String>,
+	pub arity: Arity,
+	//Where the code starts for each number of optional arguments supplied,
+	//running the defaults of those left out on the way to the body
+	pub entries: Vec<u32>,
+	//Parameters take the first slots of a frame, let bindings the rest
+	pub slots: u16,
+	pub code: Vec<u8>,
+	pub constants: Vec<Literal>,
+	//Lambdas nested in this one, instantiated by OP_CLOSURE
+	pub functions: Vec<Rc<Function>>,
+	//Where each upvalue is captured from when a closure is made
+	pub captures: Vec<Capture>,
+}
+
+//How many arguments a compiled function takes
+#[derive(Clone, Copy, Debug)]
+pub struct Arity {
+	pub required: u16,
+	pub optional: u16,
+	//Whether the arguments after those are collected into a list
+	pub rest: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Capture {
+	//A slot in the frame of the enclosing function
+	Local(u16),
+	//One of the enclosing closure's own upvalues
+	Upvalue(u16),
+}
+
+//A compiled function with the values it captured, and the
+//environment its free variables are looked up in
+pub struct Closure {
+	pub function: Rc<Function>,
+	upvalues: Vec<Cell>,
+	env: Rc<RefCell<ExecutionEnv>>,
+}
+
+//A captured variable, shared by the frame and the closures that captured it
+type Cell = Rc<RefCell<Literal>>;
+
+fn read_u16(code: &[u8], at: usize) -> u16 {
+	u16::from_le_bytes([code[at], code[at + 1]])
+}
+
+fn read_u32(code: &[u8], at: usize) -> u32 {
+	u32::from_le_bytes([code[at], code[at + 1], code[at + 2], code[at + 3]])
+}
+
+//The opcode at ip, its operand and where the next instruction starts
+fn decode(code: &[u8], ip: usize) -> (u8, usize, usize) {
+	let op = code[ip];
+	let width = OPCODES[op as usize].1;
+	let arg = match width {
+		0 => {
+			0
+		}
+		1 => {
+			code[ip + 1] as usize
+		}
+		2 => {
+			read_u16(code, ip + 1) as usize
+		}
+		_ => {
+			read_u32(code, ip + 1) as usize
+		}
+	};
+	(op, arg, ip + 1 + width)
+}
+
+impl Function {
+	//The parameters as they would be written in a lambda list
+	pub fn lambda_list(&self) -> String {
+		let required = self.arity.required as usize;
+		let optional = required + self.arity.optional as usize;
+		let mut parts = self.params[..required].to_vec();
+		if optional > required {
+			parts.push(format!("&optional"));
+			parts.extend(self.params[required..optional].iter().cloned());
+		}
+		if self.arity.rest {
+			parts.push(format!("&rest"));
+			parts.extend(self.params[optional..].iter().cloned());
+		}
+		parts.join(" ")
+	}
+	//A listing of the code, one instruction per line, followed by nested functions
+	pub fn disassemble(&self) -> String {
+		let mut out = String::new();
+		self.disassemble_into(&mut out, "");
+		out
+	}
+	fn disassemble_into(&self, out: &mut String, indent: &str) {
+		let mut ip = 0;
+		while ip < self.code.len() {
+			let (op, arg, next) = decode(&self.code, ip);
+			let (name, width) = OPCODES[op as usize];
+			let line = match op {
+				OP_CONST | OP_GET_GLOBAL | OP_SET_GLOBAL | OP_DEFINE_GLOBAL | OP_EVAL | OP_GET_ROOT | OP_SET_ROOT => {
+					format!("{}{:04} {} {} ; {}", indent, ip, name, arg, self.constants[arg].write())
+				}
+				_ if width == 0 => {
+					format!("{}{:04} {}", indent, ip, name)
+				}
+				_ => {
+					format!("{}{:04} {} {}", indent, ip, name, arg)
+				}
+			};
+			out.push_str(&line);
+			out.push('\n');
+			ip = next;
+		}
+		for (i, f) in self.functions.iter().enumerate() {
+			out.push_str(&format!("{}function {} {} ({}):\n", indent, i, f.name, f.lambda_list()));
+			f.disassemble_into(out, &format!("{}  ", indent));
+		}
+	}
+}
+
+//Compiles a closure's parameters and body, free variables of the
+//result are looked up in scope just as the tree-walker would.
+//A named closure's own name means the compiled closure, so its
+//recursive calls stay in the VM however the original is bound
+pub fn compile_closure(params: &Params, body: &Literal, scope: &Rc<RefCell<ExecutionEnv>>) -> Result<Rc<Closure>, String> {
+	let mut compiler = Compiler {
+		env: scope.clone(),
+		states: Vec::new(),
+		toplevel: false,
+	};
+	let function = compiler.function(params, std::slice::from_ref(body))?;
+	let env = if params.name == "lambda" {
+		scope.clone()
+	}
+	else {
+		ExecutionEnv::extend(scope.clone(), HashMap::new())
+	};
+	let closure = Rc::new(Closure {
+		function: Rc::new(function),
+		upvalues: Vec::new(),
+		env: env.clone(),
+	});
+	if params.name != "lambda" {
+		env.borrow_mut().add_function(params.name.clone(), Executable::BytecodeObject(closure.clone()));
+	}
+	Ok(closure)
+}
+
+//Compiles a top level form into a function of no arguments. A form the
+//compiler can't handle is kept as it is, to be tree-walked when it runs
+pub fn compile_toplevel(form: &Literal, env: &Rc<RefCell<ExecutionEnv>>) -> Rc<Function> {
+	let mut compiler = Compiler {
+		env: env.clone(),
+		states: Vec::new(),
+		toplevel: true,
+	};
+	match compiler.function(&Params::new("toplevel"), std::slice::from_ref(form)) {
+		Ok(function) => {
+			Rc::new(function)
+		}
+		Err(_) => {
+			let mut state = FnState::new(format!("toplevel"), Vec::new());
+			state.constants.push(form.clone());
+			state.emit_u16(OP_EVAL, 0);
+			state.emit(OP_RETURN);
+			Rc::new(state.finish().unwrap())
+		}
+	}
+}
+
+enum Var {
+	Local(u16),
+	Upvalue(u16),
+	Global,
+	//Named by a syntax-rules template defined at top level
+	Root,
+}
+
+//A function partway through compilation
+struct FnState {
+	name: String,
+	params: Vec<String>,
+	arity: Arity,
+	entries: Vec<u32>,
+	//Named slots of the parameters and each enclosing let, innermost last
+	scopes: Vec<Vec<(String, u16)>>,
+	slots: u16,
+	code: Vec<u8>,
+	constants: Vec<Literal>,
+	functions: Vec<Rc<Function>>,
+	captures: Vec<(String, Capture)>,
+}
+
+impl FnState {
+	fn new(name: String, params: Vec<String>) -> Self {
+		Self {
+			name,
+			scopes: vec![params.iter().enumerate().map(|(i, p)| (p.clone(), i as u16)).collect()],
+			slots: params.len() as u16,
+			arity: Arity { required: params.len() as u16, optional: 0, rest: false },
+			entries: Vec::new(),
+			params,
+			code: Vec::new(),
+			constants: Vec::new(),
+			functions: Vec::new(),
+			captures: Vec::new(),
+		}
+	}
+	fn local(&self, name: &str) -> Option<u16> {
+		self.scopes.iter().rev().flat_map(|s| s.iter().rev()).find(|(n, _)| n == name).map(|(_, slot)| *slot)
+	}
+	fn emit(&mut self, op: u8) {
+		self.code.push(op);
+	}
+	fn emit_u16(&mut self, op: u8, arg: u16) {
+		self.code.push(op);
+		self.code.extend(arg.to_le_bytes());
+	}
+	//Emits a jump with a placeholder target, returning where to patch it
+	fn emit_jump(&mut self, op: u8) -> usize {
+		self.code.push(op);
+		self.code.extend([0; 4]);
+		self.code.len() - 4
+	}
+	//Points a jump emitted earlier at the end of the code so far
+	fn patch(&mut self, at: usize) {
+		let target = (self.code.len() as u32).to_le_bytes();
+		self.code[at..at + 4].copy_from_slice(&target);
+	}
+	fn constant(&mut self, val: Literal) -> Result<u16, String> {
+		if self.constants.len() >= u16::MAX as usize {
+			return Err(format!("too many constants in one function"))
+		}
+		self.constants.push(val);
+		Ok((self.constants.len() - 1) as u16)
+	}
+	fn slot(&mut self) -> Result<u16, String> {
+		if self.slots == u16::MAX {
+			return Err(format!("too many local variables in one function"))
+		}
+		self.slots += 1;
+		Ok(self.slots - 1)
+	}
+	fn finish(self) -> Result<Function, String> {
+		let entries = if self.entries.is_empty() { vec![0] } else { self.entries };
+		Ok(Function {
+			name: self.name,
+			params: self.params,
+			arity: self.arity,
+			entries,
+			slots: self.slots,
+			code: self.code,
+			constants: self.constants,
+			functions: self.functions,
+			captures: self.captures.into_iter().map(|(_, c)| c).collect(),
+		})
+	}
+}
+
+//The message of an error raised while expanding or checking a form
+fn message(err: LispError) -> String {
+	err.to_string()
+}
+
+//Forms the compiler can't translate are an error, so the caller
+//can keep the closure on the tree-walker instead
+struct Compiler {
+	//Where macros and the forms compiled inline are looked up
+	env: Rc<RefCell<ExecutionEnv>>,
+	//The function being compiled and all those enclosing it, innermost last
+	states: Vec<FnState>,
+	//Whether the outermost function is a top level form, where definitions are global
+	toplevel: bool,
+}
+
+impl Compiler {
+	fn top(&mut self) -> &mut FnState {
+		self.states.last_mut().unwrap()
+	}
+	fn function(&mut self, params: &Params, body: &[Literal]) -> Result<Function, String> {
+		if !params.keys.is_empty() {
+			return Err(format!("closures with &key parameters can't be compiled"))
+		}
+		let names: Vec<String> = params.names();
+		if names.len() >= u16::MAX as usize {
+			return Err(format!("too many parameters in one function"))
+		}
+		let mut state = FnState::new(params.name.clone(), names);
+		state.arity = Arity {
+			required: params.required.len() as u16,
+			optional: params.optional.len() as u16,
+			rest: params.rest.is_some(),
+		};
+		self.states.push(state);
+		let compiled = self.defaults(params).and_then(|_| self.body(body, true));
+		let mut state = self.states.pop().unwrap();
+		compiled?;
+		state.emit(OP_RETURN);
+		state.finish()
+	}
+	//Code storing the defaults of optional parameters, with an entry point
+	//before each so a call runs only those of the ones it left out
+	fn defaults(&mut self, params: &Params) -> Result<(), String> {
+		for (i, (_, default)) in params.optional.iter().enumerate() {
+			let entry = self.top().code.len() as u32;
+			self.top().entries.push(entry);
+			self.expr(default, false)?;
+			self.top().emit_u16(OP_STORE_LOCAL, (params.required.len() + i) as u16);
+		}
+		let entry = self.top().code.len() as u32;
+		self.top().entries.push(entry);
+		Ok(())
+	}
+	//Definitions compile to globals only outside of any lambda or let
+	fn at_toplevel(&self) -> bool {
+		self.toplevel && self.states.len() == 1 && self.states[0].scopes.len() == 1
+	}
+	fn resolve(&mut self, name: &str) -> Var {
+		let top = self.states.len() - 1;
+		if let Some(slot) = self.states[top].local(name) {
+			return Var::Local(slot);
+		}
+		match self.upvalue(top, name) {
+			Some(i) => {
+				Var::Upvalue(i)
+			}
+			None => {
+				Var::Global
+			}
+		}
+	}
+	//Identifiers a syntax-rules template left marked are looked up where the macro was defined
+	fn variable(&mut self, form: &Literal, name: &str) -> Result<Var, String> {
+		match form {
+			Literal::Syntax(_, _, scope) if Rc::ptr_eq(&scope.0, &ExecutionEnv::global(&self.env)) => {
+				Ok(Var::Root)
+			}
+			Literal::Syntax(_, _, _) => {
+				Err(format!("{} comes from a macro defined in a local scope, which can't be compiled", name))
+			}
+			_ => {
+				Ok(self.resolve(name))
+			}
+		}
+	}
+	//Finds a variable of an enclosing function, capturing it
+	//through every function in between
+	fn upvalue(&mut self, state: usize, name: &str) -> Option<u16> {
+		if let Some(i) = self.states[state].captures.iter().position(|(n, _)| n == name) {
+			return Some(i as u16);
+		}
+		if state == 0 {
+			return None;
+		}
+		let capture = match self.states[state - 1].local(name) {
+			Some(slot) => {
+				Capture::Local(slot)
+			}
+			None => {
+				Capture::Upvalue(self.upvalue(state - 1, name)?)
+			}
+		};
+		self.states[state].captures.push((name.to_string(), capture));
+		Some((self.states[state].captures.len() - 1) as u16)
+	}
+	fn expr(&mut self, form: &Literal, tail: bool) -> Result<(), String> {
+		match form {
+			Literal::Atom(name, _) | Literal::Syntax(name, _, _) => {
+				match self.variable(form, name)? {
+					Var::Local(slot) => {
+						self.top().emit_u16(OP_GET_LOCAL, slot);
+					}
+					Var::Upvalue(i) => {
+						self.top().emit_u16(OP_GET_UPVALUE, i);
+					}
+					Var::Global => {
+						let name = self.top().constant(Literal::Atom(name.clone(), None))?;
+						self.top().emit_u16(OP_GET_GLOBAL, name);
+					}
+					Var::Root => {
+						let name = self.top().constant(Literal::Atom(name.clone(), None))?;
+						self.top().emit_u16(OP_GET_ROOT, name);
+					}
+				}
+				Ok(())
+			}
+			Literal::List(items, _) if !items.is_empty() => {
+				//Macros are expanded now, and builtins that take their
+				//arguments unevaluated are compiled inline
+				if let Literal::Atom(name, _) = &items[0] {
+					if let Var::Global = self.resolve(name) {
+						let value = self.env.borrow().lookup_value(name);
+						if let Ok(Literal::Func(exec)) = value {
+							match exec.as_ref() {
+								Executable::Macro(_, _, _) => {
+									let expansion = exec.expand(items[1..].to_vec()).map_err(message)?;
+									return self.expr(&expansion, tail);
+								}
+								Executable::SyntaxRules(rules) => {
+									let expansion = expand::expand_use(rules, form).map_err(message)?;
+									return self.expr(&expansion, tail);
+								}
+								Executable::Builtin(_) | Executable::SpecialForm(_) => {
+									return self.form(name, &items[1..], tail);
+								}
+								_ => {}
+							}
+						}
+					}
+				}
+				self.call(items, tail)
+			}
+			Literal::List(_, _) | Literal::Nil => {
+				self.top().emit(OP_NIL);
+				Ok(())
+			}
+			Literal::Vector(_, _) | Literal::Map(_, _) | Literal::Set(_, _) => {
+				let forms = form.collection_forms().unwrap();
+				if forms.len() >= u16::MAX as usize {
+					return Err(format!("too many elements in one collection"))
+				}
+				for item in forms.iter() {
+					self.expr(item, false)?;
+				}
+				let op = match form {
+					Literal::Vector(_, _) => {
+						OP_VECTOR
+					}
+					Literal::Map(_, _) => {
+						OP_MAP
+					}
+					_ => {
+						OP_SET
+					}
+				};
+				self.top().emit_u16(op, forms.len() as u16);
+				Ok(())
+			}
+			other => {
+				let val = self.top().constant(other.clone())?;
+				self.top().emit_u16(OP_CONST, val);
+				Ok(())
+			}
+		}
+	}
+	fn call(&mut self, items: &[Literal], tail: bool) -> Result<(), String> {
+		let argc = items.len() - 1;
+		if argc > u8::MAX as usize {
+			return Err(format!("too many arguments in one call"))
+		}
+		for item in items {
+			self.expr(item, false)?;
+		}
+		self.top().emit(if tail { OP_TAIL_CALL } else { OP_CALL });
+		self.top().code.push(argc as u8);
+		Ok(())
+	}
+	fn form(&mut self, name: &str, args: &[Literal], tail: bool) -> Result<(), String> {
+		match name {
+			"quote" if args.len() == 1 => {
+				let val = self.top().constant(args[0].clone())?;
+				self.top().emit_u16(OP_CONST, val);
+				Ok(())
+			}
+			"progn" => {
+				self.body(args, tail)
+			}
+			"if" => {
+				self.if_form(args, tail)
+			}
+			"when" => {
+				self.conditional(args, true, tail)
+			}
+			"unless" => {
+				self.conditional(args, false, tail)
+			}
+			"cond" => {
+				self.cond(args, tail)
+			}
+			"and" => {
+				self.logical(args, true, tail)
+			}
+			"or" => {
+				self.logical(args, false, tail)
+			}
+			"let" => {
+				self.let_form(args, "let", false, tail)
+			}
+			"let*" => {
+				self.let_form(args, "let*", true, tail)
+			}
+			"letrec" => {
+				self.letrec(args, tail)
+			}
+			"lambda" | "fn" => {
+				self.lambda(args)
+			}
+			"set!" => {
+				self.set(args)
+			}
+			"defn" if self.at_toplevel() && args.len() == 3 => {
+				let params = Params::parse(&args[1], "defn").map_err(message)?;
+				self.closure(&Params { name: args[0].print(), ..params }, &args[2..])?;
+				self.define_global(&args[0])
+			}
+			"define" if self.at_toplevel() && args.len() == 2 => {
+				match &args[0] {
+					//(define (name args...) body) is shorthand for defn
+					Literal::List(l, _) if !l.is_empty() => {
+						self.lambda(&[Literal::List(l[1..].to_vec(), None), args[1].clone()])?;
+						self.define_global(&l[0])
+					}
+					Literal::Atom(_, _) => {
+						self.expr(&args[1], false)?;
+						self.define_global(&args[0])
+					}
+					_ => {
+						Err(format!("form 'define' requires a name"))
+					}
+				}
+			}
+			_ => {
+				Err(format!("form '{}' can't be compiled", name))
+			}
+		}
+	}
+	//Forms evaluated in order for the value of the last
+	fn body(&mut self, forms: &[Literal], tail: bool) -> Result<(), String> {
+		match forms.split_last() {
+			Some((last, init)) => {
+				for form in init {
+					self.expr(form, false)?;
+					self.top().emit(OP_POP);
+				}
+				self.expr(last, tail)
+			}
+			None => {
+				Err(format!("form progn requires at least one argument"))
+			}
+		}
+	}
+	fn if_form(&mut self, args: &[Literal], tail: bool) -> Result<(), String> {
+		if args.len() != 2 && args.len() != 3 {
+			return Err(format!("form 'if' requires 2 or 3 arguments"))
+		}
+		self.expr(&args[0], false)?;
+		let otherwise = self.top().emit_jump(OP_JUMP_IF_FALSE);
+		self.expr(&args[1], tail)?;
+		let end = self.top().emit_jump(OP_JUMP);
+		self.top().patch(otherwise);
+		match args.get(2) {
+			Some(e) => {
+				self.expr(e, tail)?;
+			}
+			None => {
+				self.top().emit(OP_NIL);
+			}
+		}
+		self.top().patch(end);
+		Ok(())
+	}
+	//when and unless, running the body if the test's truthiness matches expected
+	fn conditional(&mut self, args: &[Literal], expected: bool, tail: bool) -> Result<(), String> {
+		if args.len() < 2 {
+			return Err(format!("forms 'when' and 'unless' require a test and at least one body form"))
+		}
+		self.expr(&args[0], false)?;
+		let skip = self.top().emit_jump(if expected { OP_JUMP_IF_FALSE } else { OP_JUMP_IF_TRUE });
+		self.body(&args[1..], tail)?;
+		let end = self.top().emit_jump(OP_JUMP);
+		self.top().patch(skip);
+		self.top().emit(OP_NIL);
+		self.top().patch(end);
+		Ok(())
+	}
+	fn cond(&mut self, args: &[Literal], tail: bool) -> Result<(), String> {
+		let mut ends = Vec::new();
+		let mut exhaustive = false;
+		for clause in args {
+			let forms = match clause {
+				Literal::List(l, _) if !l.is_empty() => {
+					l
+				}
+				_ => {
+					return Err(format!("form 'cond' requires each clause to be a non-empty list"))
+				}
+			};
+			if let Literal::Atom(s, _) = &forms[0] {
+				if s == "else" {
+					if forms.len() == 1 {
+						let val = self.top().constant(forms[0].clone())?;
+						self.top().emit_u16(OP_CONST, val);
+					}
+					else {
+						self.body(&forms[1..], tail)?;
+					}
+					exhaustive = true;
+					break;
+				}
+			}
+			self.expr(&forms[0], false)?;
+			if forms.len() == 1 {
+				//A clause without a body returns its test
+				self.top().emit(OP_DUP);
+				ends.push(self.top().emit_jump(OP_JUMP_IF_TRUE));
+				self.top().emit(OP_POP);
+			}
+			else {
+				let next = self.top().emit_jump(OP_JUMP_IF_FALSE);
+				self.body(&forms[1..], tail)?;
+				ends.push(self.top().emit_jump(OP_JUMP));
+				self.top().patch(next);
+			}
+		}
+		if !exhaustive {
+			self.top().emit(OP_NIL);
+		}
+		for end in ends {
+			self.top().patch(end);
+		}
+		Ok(())
+	}
+	//and/or, stopping at the first operand whose truthiness isn't short
+	fn logical(&mut self, args: &[Literal], short: bool, tail: bool) -> Result<(), String> {
+		let (last, init) = match args.split_last() {
+			Some(parts) => {
+				parts
+			}
+			None => {
+				let val = self.top().constant(Literal::Bool(short))?;
+				self.top().emit_u16(OP_CONST, val);
+				return Ok(())
+			}
+		};
+		let mut ends = Vec::new();
+		for operand in init {
+			self.expr(operand, false)?;
+			self.top().emit(OP_DUP);
+			ends.push(self.top().emit_jump(if short { OP_JUMP_IF_FALSE } else { OP_JUMP_IF_TRUE }));
+			self.top().emit(OP_POP);
+		}
+		self.expr(last, tail)?;
+		for end in ends {
+			self.top().patch(end);
+		}
+		Ok(())
+	}
+	//let bindings get fresh slots, visible to later initializers only in let*
+	fn let_form(&mut self, args: &[Literal], name: &str, sequential: bool, tail: bool) -> Result<(), String> {
+		let (bindings, body) = liblisp::let_parts(args.to_vec(), name).map_err(message)?;
+		self.top().scopes.push(Vec::new());
+		let mut names = Vec::new();
+		for (var, init) in bindings {
+			self.expr(&init, false)?;
+			let slot = self.top().slot()?;
+			self.top().emit_u16(OP_STORE_LOCAL, slot);
+			if sequential {
+				self.top().scopes.last_mut().unwrap().push((var, slot));
+			}
+			else {
+				names.push((var, slot));
+			}
+		}
+		self.top().scopes.last_mut().unwrap().extend(names);
+		self.body(&body, tail)?;
+		self.top().scopes.pop();
+		Ok(())
+	}
+	//Every name is bound to nil before the initializers run, so lambdas
+	//among them capture the bindings the initializers then assign
+	fn letrec(&mut self, args: &[Literal], tail: bool) -> Result<(), String> {
+		let (bindings, body) = liblisp::let_parts(args.to_vec(), "letrec").map_err(message)?;
+		self.top().scopes.push(Vec::new());
+		let mut slots = Vec::new();
+		for (var, _) in bindings.iter() {
+			let slot = self.top().slot()?;
+			self.top().emit(OP_NIL);
+			self.top().emit_u16(OP_STORE_LOCAL, slot);
+			self.top().scopes.last_mut().unwrap().push((var.clone(), slot));
+			slots.push(slot);
+		}
+		for ((_, init), slot) in bindings.iter().zip(slots) {
+			self.expr(init, false)?;
+			self.top().emit_u16(OP_SET_LOCAL, slot);
+			self.top().emit(OP_POP);
+		}
+		self.body(&body, tail)?;
+		self.top().scopes.pop();
+		Ok(())
+	}
+	fn lambda(&mut self, args: &[Literal]) -> Result<(), String> {
+		if args.len() != 2 {
+			return Err(format!("form 'lambda' requires 2 arguments"))
+		}
+		let params = Params::parse(&args[0], "lambda").map_err(message)?;
+		self.closure(&params, &args[1..])
+	}
+	fn closure(&mut self, params: &Params, body: &[Literal]) -> Result<(), String> {
+		let function = self.function(params, body)?;
+		if self.top().functions.len() >= u16::MAX as usize {
+			return Err(format!("too many nested functions in one function"))
+		}
+		self.top().functions.push(Rc::new(function));
+		let index = (self.top().functions.len() - 1) as u16;
+		self.top().emit_u16(OP_CLOSURE, index);
+		Ok(())
+	}
+	fn define_global(&mut self, name: &Literal) -> Result<(), String> {
+		let name = self.top().constant(Literal::Atom(name.print(), None))?;
+		self.top().emit_u16(OP_DEFINE_GLOBAL, name);
+		Ok(())
+	}
+	fn set(&mut self, args: &[Literal]) -> Result<(), String> {
+		let name = match args {
+			[Literal::Atom(name, _), _] | [Literal::Syntax(name, _, _), _] => {
+				name
+			}
+			_ => {
+				return Err(format!("form 'set!' requires a symbol and a value"))
+			}
+		};
+		self.expr(&args[1], false)?;
+		match self.variable(&args[0], name)? {
+			Var::Local(slot) => {
+				self.top().emit_u16(OP_SET_LOCAL, slot);
+			}
+			Var::Upvalue(i) => {
+				self.top().emit_u16(OP_SET_UPVALUE, i);
+			}
+			Var::Global => {
+				let name = self.top().constant(Literal::Atom(name.clone(), None))?;
+				self.top().emit_u16(OP_SET_GLOBAL, name);
+			}
+			Var::Root => {
+				let name = self.top().constant(Literal::Atom(name.clone(), None))?;
+				self.top().emit_u16(OP_SET_ROOT, name);
+			}
+		}
+		Ok(())
+	}
+}
+
+//The cell a slot was captured into, making one the first time it is
+fn capture(stack: &[Literal], cells: &mut Vec<Option<Cell>>, at: usize) -> Cell {
+	if cells.len() <= at {
+		cells.resize(at + 1, None);
+	}
+	cells[at].get_or_insert_with(|| Rc::new(RefCell::new(stack[at].clone()))).clone()
+}
+
+//The state of a caller, saved while the compiled function it called runs
+struct Frame {
+	closure: Rc<Closure>,
+	ip: usize,
+	base: usize,
+}
+
+//Checks a call passes a number of arguments the function takes
+fn check_arity(function: &Function, argc: usize) -> Result<(), LispError> {
+	let required = function.arity.required as usize;
+	let max = required + function.arity.optional as usize;
+	if argc < required || (!function.arity.rest && argc > max) {
+		let max = if function.arity.rest { None } else { Some(max) };
+		return Err(env::arity_error(&function.name, required, max, argc))
+	}
+	Ok(())
+}
+
+//Fits the arguments from base up to the function's parameters, which
+//check_arity has allowed, and reserves its other slots. Returns where
+//its code starts given how many optional arguments were supplied
+fn enter(stack: &mut Vec<Literal>, function: &Function, base: usize) -> usize {
+	let argc = stack.len() - base;
+	let required = function.arity.required as usize;
+	let fixed = required + function.arity.optional as usize;
+	if function.arity.rest {
+		let rest = stack.split_off(base + argc.min(fixed));
+		stack.resize(base + fixed, Literal::Nil);
+		stack.push(Literal::List(rest, None));
+	}
+	stack.resize(base + function.slots as usize, Literal::Nil);
+	function.entries[argc.min(fixed) - required] as usize
+}
+
+//Calls anything other than a compiled function
+fn call(callee: Literal, env: &Rc<RefCell<ExecutionEnv>>, args: Vec<Literal>, primitives: &[LispPrimitive]) -> Result<Literal, LispError> {
+	match callee {
+		Literal::Func(exec) => {
+			match exec.as_ref() {
+				Executable::Primitive(num) => {
+					primitives[*num as usize](args)
+				}
+				_ => {
+					exec.apply(env.clone(), args)
+				}
+			}
+		}
+		other => {
+			Err(LispError::new(ErrorKind::TypeError, format!("{} is not a function", other.print())))
+		}
+	}
+}
+
+//Runs a compiled closure. A frame's slots sit on the stack just above
+//its callee, calls between compiled functions save the caller's frame
+//rather than recursing, and tail calls replace the current one
+pub fn run(closure: Rc<Closure>, args: Vec<Literal>) -> Result<Literal, LispError> {
+	check_arity(&closure.function, args.len())?;
+	execute(closure, args, false)
+}
+
+//Runs a closure whose arguments have been checked. An error ends every
+//frame of the run, and is marked with the function of each in turn.
+//A top level form isn't a call, so it is left out unless a tail call replaced it
+fn execute(closure: Rc<Closure>, args: Vec<Literal>, toplevel: bool) -> Result<Literal, LispError> {
+	let primitives = liblisp::get_primitives();
+	let first = closure.clone();
+	//Stands in for the callee below the first frame's arguments
+	let mut stack = vec![Literal::Nil];
+	stack.extend(args);
+	//The cells of captured slots, by their place on the stack
+	let mut cells: Vec<Option<Cell>> = Vec::new();
+	let mut frames: Vec<Frame> = Vec::new();
+	let mut closure = closure;
+	let mut base = 1;
+	let mut ip = enter(&mut stack, &closure.function, base);
+	let err = loop {
+		let (op, arg, next) = decode(&closure.function.code, ip);
+		ip = next;
+		let mut returning = false;
+		match op {
+			OP_CONST => {
+				stack.push(closure.function.constants[arg].clone());
+			}
+			OP_NIL => {
+				stack.push(Literal::Nil);
+			}
+			OP_GET_LOCAL => {
+				let val = match cells.get(base + arg) {
+					Some(Some(cell)) => {
+						cell.borrow().clone()
+					}
+					_ => {
+						stack[base + arg].clone()
+					}
+				};
+				stack.push(val);
+			}
+			OP_STORE_LOCAL => {
+				stack[base + arg] = stack.pop().unwrap();
+				if let Some(cell) = cells.get_mut(base + arg) {
+					*cell = None;
+				}
+			}
+			OP_SET_LOCAL => {
+				let val = stack.last().unwrap().clone();
+				match cells.get(base + arg) {
+					Some(Some(cell)) => {
+						*cell.borrow_mut() = val;
+					}
+					_ => {
+						stack[base + arg] = val;
+					}
+				}
+			}
+			OP_GET_UPVALUE => {
+				let val = closure.upvalues[arg].borrow().clone();
+				stack.push(val);
+			}
+			OP_SET_UPVALUE => {
+				*closure.upvalues[arg].borrow_mut() = stack.last().unwrap().clone();
+			}
+			OP_GET_GLOBAL => {
+				let value = closure.env.borrow().lookup_value(&closure.function.constants[arg].print());
+				match value {
+					Ok(v) => {
+						stack.push(v);
+					}
+					Err(e) => {
+						break e;
+					}
+				}
+			}
+			OP_SET_GLOBAL => {
+				let val = stack.last().unwrap().clone();
+				let result = closure.env.borrow_mut().set(&closure.function.constants[arg].print(), val);
+				if let Err(e) = result {
+					break e;
+				}
+			}
+			OP_GET_ROOT => {
+				let value = ExecutionEnv::global(&closure.env).borrow().lookup_value(&closure.function.constants[arg].print());
+				match value {
+					Ok(v) => {
+						stack.push(v);
+					}
+					Err(e) => {
+						break e;
+					}
+				}
+			}
+			OP_SET_ROOT => {
+				let val = stack.last().unwrap().clone();
+				let result = ExecutionEnv::global(&closure.env).borrow_mut().set(&closure.function.constants[arg].print(), val);
+				if let Err(e) = result {
+					break e;
+				}
+			}
+			OP_DEFINE_GLOBAL => {
+				let name = closure.function.constants[arg].clone();
+				let val = stack.pop().unwrap();
+				closure.env.borrow_mut().define(name.print(), val);
+				stack.push(name);
+			}
+			OP_EVAL => {
+				match ExecutionEnv::evaluate(closure.env.clone(), closure.function.constants[arg].clone()) {
+					Ok(v) => {
+						stack.push(v);
+					}
+					Err(e) => {
+						break e;
+					}
+				}
+			}
+			OP_POP => {
+				stack.pop();
+			}
+			OP_DUP => {
+				stack.push(stack.last().unwrap().clone());
+			}
+			OP_JUMP => {
+				ip = arg;
+			}
+			OP_JUMP_IF_FALSE => {
+				if !stack.pop().unwrap().is_truthy() {
+					ip = arg;
+				}
+			}
+			OP_JUMP_IF_TRUE => {
+				if stack.pop().unwrap().is_truthy() {
+					ip = arg;
+				}
+			}
+			OP_CALL | OP_TAIL_CALL => {
+				let at = stack.len() - arg - 1;
+				let callee = stack[at].clone();
+				if let Literal::Func(exec) = &callee {
+					if let Executable::BytecodeObject(next) = exec.as_ref() {
+						match check_arity(&next.function, arg) {
+							Ok(()) => {
+								if op == OP_TAIL_CALL {
+									stack.drain(base - 1..at);
+									cells.truncate(base - 1);
+									closure = next.clone();
+								}
+								else if frames.len() >= env::MAX_FRAMES {
+									break env::recursion_error();
+								}
+								else {
+									frames.push(Frame {
+										closure: std::mem::replace(&mut closure, next.clone()),
+										ip,
+										base,
+									});
+									base = at + 1;
+								}
+								ip = enter(&mut stack, &closure.function, base);
+								continue;
+							}
+							Err(e) => {
+								break e;
+							}
+						}
+					}
+				}
+				let args = stack.split_off(at + 1);
+				stack.pop();
+				match call(callee, &closure.env, args, &primitives) {
+					Ok(v) => {
+						stack.push(v);
+					}
+					Err(e) => {
+						break e;
+					}
+				}
+				returning = op == OP_TAIL_CALL;
+			}
+			OP_VECTOR | OP_MAP | OP_SET => {
+				let items = stack.split_off(stack.len() - arg);
+				let made = match op {
+					OP_VECTOR => {
+						Literal::Vector(items, None)
+					}
+					OP_MAP => {
+						Literal::Map(Vec::new(), None).rebuild_collection(items)
+					}
+					_ => {
+						Literal::set_from(items, None)
+					}
+				};
+				stack.push(made);
+			}
+			OP_RETURN => {
+				returning = true;
+			}
+			OP_CLOSURE => {
+				let function = closure.function.functions[arg].clone();
+				let upvalues = function.captures.iter().map(|c| {
+					match c {
+						Capture::Local(slot) => {
+							capture(&stack, &mut cells, base + *slot as usize)
+						}
+						Capture::Upvalue(i) => {
+							closure.upvalues[*i as usize].clone()
+						}
+					}
+				}).collect();
+				let env = closure.env.clone();
+				stack.push(Literal::Func(Rc::new(Executable::BytecodeObject(Rc::new(Closure { function, upvalues, env })))));
+			}
+			_ => {
+				break LispError::new(ErrorKind::CompileError, format!("bytecode: unknown opcode {}", op))
+			}
+		}
+		if returning {
+			let result = stack.pop().unwrap();
+			stack.truncate(base - 1);
+			cells.truncate(base - 1);
+			match frames.pop() {
+				Some(frame) => {
+					closure = frame.closure;
+					ip = frame.ip;
+					base = frame.base;
+					stack.push(result);
+				}
+				None => {
+					return Ok(result)
+				}
+			}
+		}
+	};
+	let left = std::iter::once(&closure).chain(frames.iter().rev().map(|f| &f.closure));
+	Err(left.filter(|c| !(toplevel && Rc::ptr_eq(c, &first))).fold(err, |err, c| unwind(err, &c.function.name)))
+}
+
+//Runs the top level functions of a module in order, returning the last value
+pub fn run_module(functions: &[Rc<Function>], env: &Rc<RefCell<ExecutionEnv>>) -> Result<Literal, LispError> {
+	let mut result = Literal::Nil;
+	for function in functions {
+		let closure = Closure {
+			function: function.clone(),
+			upvalues: Vec::new(),
+			env: env.clone(),
+		};
+		result = execute(Rc::new(closure), Vec::new(), true)?;
+	}
+	Ok(result)
+}
+
+//Compiled modules are stored as the magic bytes and a u16 format version,
+//then a u32 count of top level functions. A function is its parameter
+//names, slot count, constant pool, nested functions, captures and code.
+//Counts and lengths are u16 unless they can exceed it, then u32
+const MAGIC: &[u8; 4] = b"FLC\0";
+const FORMAT_VERSION: u16 = 8;
+
+//Constant pool entries start with one of these tags
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_FIXNUM: u8 = 3;
+const TAG_FLOAT: u8 = 4;
+//Bignums and rationals, written as they print
+const TAG_EXACT: u8 = 5;
+const TAG_STRING: u8 = 6;
+const TAG_ATOM: u8 = 7;
+const TAG_LIST: u8 = 8;
+//Collections are followed by a u32 count and their forms, as lists are
+const TAG_VECTOR: u8 = 9;
+const TAG_MAP: u8 = 10;
+const TAG_SET: u8 = 11;
+const TAG_KEYWORD: u8 = 12;
+
+//Whether bytes look like a compiled module rather than source
+pub fn is_module(bytes: &[u8]) -> bool {
+	bytes.starts_with(MAGIC)
+}
+
+pub fn write_module(functions: &[Rc<Function>]) -> Result<Vec<u8>, String> {
+	let mut out = MAGIC.to_vec();
+	out.extend(FORMAT_VERSION.to_le_bytes());
+	out.extend((functions.len() as u32).to_le_bytes());
+	for function in functions {
+		write_function(&mut out, function)?;
+	}
+	Ok(out)
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+	out.extend((s.len() as u32).to_le_bytes());
+	out.extend(s.as_bytes());
+}
+
+fn write_function(out: &mut Vec<u8>, function: &Function) -> Result<(), String> {
+	write_str(out, &function.name);
+	out.extend((function.params.len() as u16).to_le_bytes());
+	for param in &function.params {
+		write_str(out, param);
+	}
+	out.extend(function.arity.required.to_le_bytes());
+	out.extend(function.arity.optional.to_le_bytes());
+	out.push(function.arity.rest as u8);
+	out.extend((function.entries.len() as u16).to_le_bytes());
+	for entry in &function.entries {
+		out.extend(entry.to_le_bytes());
+	}
+	out.extend(function.slots.to_le_bytes());
+	out.extend((function.constants.len() as u16).to_le_bytes());
+	for constant in &function.constants {
+		write_literal(out, constant)?;
+	}
+	out.extend((function.functions.len() as u16).to_le_bytes());
+	for nested in &function.functions {
+		write_function(out, nested)?;
+	}
+	out.extend((function.captures.len() as u16).to_le_bytes());
+	for capture in &function.captures {
+		match capture {
+			Capture::Local(slot) => {
+				out.push(0);
+				out.extend(slot.to_le_bytes());
+			}
+			Capture::Upvalue(i) => {
+				out.push(1);
+				out.extend(i.to_le_bytes());
+			}
+		}
+	}
+	out.extend((function.code.len() as u32).to_le_bytes());
+	out.extend(&function.code);
+	Ok(())
+}
+
+fn write_literal(out: &mut Vec<u8>, val: &Literal) -> Result<(), String> {
+	match val {
+		Literal::Nil => {
+			out.push(TAG_NIL);
+		}
+		Literal::Bool(b) => {
+			out.push(if *b { TAG_TRUE } else { TAG_FALSE });
+		}
+		Literal::Num(Number::Fixnum(i)) => {
+			out.push(TAG_FIXNUM);
+			out.extend(i.to_le_bytes());
+		}
+		Literal::Num(Number::Float(f)) => {
+			out.push(TAG_FLOAT);
+			out.extend(f.to_le_bytes());
+		}
+		Literal::Num(n) => {
+			out.push(TAG_EXACT);
+			write_str(out, &n.print());
+		}
+		Literal::String(s) => {
+			out.push(TAG_STRING);
+			write_str(out, s);
+		}
+		Literal::Keyword(k) => {
+			out.push(TAG_KEYWORD);
+			write_str(out, k);
+		}
+		//Marks only matter while expanding, which is done by now
+		Literal::Atom(s, _) | Literal::Syntax(s, _, _) => {
+			out.push(TAG_ATOM);
+			write_str(out, s);
+		}
+		Literal::List(l, _) => {
+			out.push(TAG_LIST);
+			out.extend((l.len() as u32).to_le_bytes());
+			for item in l {
+				write_literal(out, item)?;
+			}
+		}
+		Literal::Vector(_, _) | Literal::Map(_, _) | Literal::Set(_, _) => {
+			let forms = val.collection_forms().unwrap();
+			out.push(match val {
+				Literal::Vector(_, _) => {
+					TAG_VECTOR
+				}
+				Literal::Map(_, _) => {
+					TAG_MAP
+				}
+				_ => {
+					TAG_SET
+				}
+			});
+			out.extend((forms.len() as u32).to_le_bytes());
+			for item in forms.iter() {
+				write_literal(out, item)?;
+			}
+		}
+		Literal::Func(_) | Literal::Err(_) => {
+			return Err(format!("the constant {} can't be written to a module", val.print()))
+		}
+	}
+	Ok(())
+}
+
+//Reads a module back, checking everything the VM relies on
+//so a damaged file is an error rather than a crash
+pub fn read_module(bytes: &[u8]) -> Result<Vec<Rc<Function>>, String> {
+	if !is_module(bytes) {
+		return Err(format!("not a compiled ferrolisp module"))
+	}
+	let mut reader = ModuleReader {
+		bytes,
+		pos: MAGIC.len(),
+	};
+	let version = reader.u16()?;
+	if version != FORMAT_VERSION {
+		return Err(format!("module has format version {}, but this ferrolisp reads version {}", version, FORMAT_VERSION))
+	}
+	let count = reader.u32()?;
+	let mut functions = Vec::new();
+	for _ in 0..count {
+		let function = reader.function()?;
+		if !function.captures.is_empty() {
+			return Err(format!("a top level function can't capture variables"))
+		}
+		//Top level functions are run with no arguments
+		if !function.params.is_empty() {
+			return Err(format!("a top level function can't take arguments"))
+		}
+		functions.push(Rc::new(function));
+	}
+	if reader.pos != bytes.len() {
+		return Err(format!("unexpected data after the last function at byte {}", reader.pos))
+	}
+	Ok(functions)
+}
+
+struct ModuleReader<'a> {
+	bytes: &'a [u8],
+	pos: usize,
+}
+
+impl<'a> ModuleReader<'a> {
+	fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+		if self.bytes.len() - self.pos < n {
+			return Err(format!("module is truncated at byte {}", self.bytes.len()))
+		}
+		self.pos += n;
+		Ok(&self.bytes[self.pos - n..self.pos])
+	}
+	fn u8(&mut self) -> Result<u8, String> {
+		Ok(self.take(1)?[0])
+	}
+	fn u16(&mut self) -> Result<u16, String> {
+		Ok(read_u16(self.take(2)?, 0))
+	}
+	fn u32(&mut self) -> Result<u32, String> {
+		Ok(read_u32(self.take(4)?, 0))
+	}
+	fn string(&mut self) -> Result<String, String> {
+		let len = self.u32()? as usize;
+		let at = self.pos;
+		String::from_utf8(self.take(len)?.to_vec()).map_err(|_| format!("invalid UTF-8 in string at byte {}", at))
+	}
+	fn literal(&mut self) -> Result<Literal, String> {
+		let at = self.pos;
+		match self.u8()? {
+			TAG_NIL => {
+				Ok(Literal::Nil)
+			}
+			TAG_FALSE => {
+				Ok(Literal::Bool(false))
+			}
+			TAG_TRUE => {
+				Ok(Literal::Bool(true))
+			}
+			TAG_FIXNUM => {
+				let b = self.take(8)?;
+				Ok(Literal::Num(Number::Fixnum(i64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))))
+			}
+			TAG_FLOAT => {
+				let b = self.take(8)?;
+				Ok(Literal::Num(Number::Float(f64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))))
+			}
+			TAG_EXACT => {
+				let s = self.string()?;
+				match Number::parse(&s) {
+					Some(n) if n.is_exact() => {
+						Ok(Literal::Num(n))
+					}
+					_ => {
+						Err(format!("malformed number {:?} at byte {}", s, at))
+					}
+				}
+			}
+			TAG_STRING => {
+				Ok(Literal::String(self.string()?))
+			}
+			TAG_ATOM => {
+				Ok(Literal::Atom(self.string()?, None))
+			}
+			TAG_KEYWORD => {
+				Ok(Literal::Keyword(self.string()?))
+			}
+			TAG_LIST => {
+				let len = self.u32()?;
+				let mut items = Vec::new();
+				for _ in 0..len {
+					items.push(self.literal()?);
+				}
+				Ok(Literal::List(items, None))
+			}
+			TAG_VECTOR | TAG_MAP | TAG_SET => {
+				let tag = self.bytes[at];
+				let len = self.u32()?;
+				if tag == TAG_MAP && !len.is_multiple_of(2) {
+					return Err(format!("map with an odd number of forms at byte {}", at))
+				}
+				let mut items = Vec::new();
+				for _ in 0..len {
+					items.push(self.literal()?);
+				}
+				let kind = match tag {
+					TAG_VECTOR => {
+						Literal::Vector(Vec::new(), None)
+					}
+					TAG_MAP => {
+						Literal::Map(Vec::new(), None)
+					}
+					_ => {
+						Literal::Set(Vec::new(), None)
+					}
+				};
+				Ok(kind.with_forms(items))
+			}
+			tag => {
+				Err(format!("unknown constant tag {} at byte {}", tag, at))
+			}
+		}
+	}
+	fn function(&mut self) -> Result<Function, String> {
+		let at = self.pos;
+		let name = self.string()?;
+		let mut params = Vec::new();
+		for _ in 0..self.u16()? {
+			params.push(self.string()?);
+		}
+		let arity = Arity {
+			required: self.u16()?,
+			optional: self.u16()?,
+			rest: self.u8()? != 0,
+		};
+		let mut entries = Vec::new();
+		for _ in 0..self.u16()? {
+			entries.push(self.u32()?);
+		}
+		let slots = self.u16()?;
+		let mut constants = Vec::new();
+		for _ in 0..self.u16()? {
+			constants.push(self.literal()?);
+		}
+		let mut functions = Vec::new();
+		for _ in 0..self.u16()? {
+			functions.push(Rc::new(self.function()?));
+		}
+		let mut captures = Vec::new();
+		for _ in 0..self.u16()? {
+			let kind = self.u8()?;
+			let index = self.u16()?;
+			//validate checks the index against the enclosing function
+			let capture = match kind {
+				0 => {
+					Capture::Local(index)
+				}
+				1 => {
+					Capture::Upvalue(index)
+				}
+				_ => {
+					return Err(format!("invalid capture in function at byte {}", at))
+				}
+			};
+			captures.push(capture);
+		}
+		let len = self.u32()? as usize;
+		let code = self.take(len)?.to_vec();
+		let function = Function {
+			name,
+			params,
+			arity,
+			entries,
+			slots,
+			code,
+			constants,
+			functions,
+			captures,
+		};
+		validate(&function).map_err(|e| format!("{} in function at byte {}", e, at))?;
+		Ok(function)
+	}
+}
+
+//Checks that every instruction is known and complete, every operand
+//is in range and jumps land on instructions, that the code can't
+//run off its end and never pops more values than it pushed
+fn validate(function: &Function) -> Result<(), String> {
+	if (function.slots as usize) < function.params.len() {
+		return Err(format!("fewer slots than parameters"))
+	}
+	let arity = function.arity;
+	if function.params.len() != (arity.required + arity.optional) as usize + arity.rest as usize {
+		return Err(format!("parameters don't match the arity"))
+	}
+	if function.entries.len() != arity.optional as usize + 1 {
+		return Err(format!("wrong number of entry points"))
+	}
+	for nested in &function.functions {
+		for capture in &nested.captures {
+			if let Capture::Upvalue(i) = capture {
+				if *i as usize >= function.captures.len() {
+					return Err(format!("capture of missing upvalue {}", i))
+				}
+			}
+			if let Capture::Local(slot) = capture {
+				if *slot >= function.slots {
+					return Err(format!("capture of missing slot {}", slot))
+				}
+			}
+		}
+	}
+	let code = &function.code;
+	let mut starts = HashSet::new();
+	let mut targets = Vec::new();
+	let mut last = None;
+	let mut ip = 0;
+	while ip < code.len() {
+		let op = code[ip];
+		let width = match OPCODES.get(op as usize) {
+			Some((_, width)) => {
+				*width
+			}
+			None => {
+				return Err(format!("unknown opcode {} at {}", op, ip))
+			}
+		};
+		if ip + width >= code.len() && width > 0 {
+			return Err(format!("incomplete instruction at {}", ip))
+		}
+		let (_, arg, next) = decode(code, ip);
+		let limit = match op {
+			OP_CONST | OP_GET_GLOBAL | OP_SET_GLOBAL | OP_DEFINE_GLOBAL | OP_EVAL | OP_GET_ROOT | OP_SET_ROOT => {
+				function.constants.len()
+			}
+			OP_GET_LOCAL | OP_STORE_LOCAL | OP_SET_LOCAL => {
+				function.slots as usize
+			}
+			OP_GET_UPVALUE | OP_SET_UPVALUE => {
+				function.captures.len()
+			}
+			OP_CLOSURE => {
+				function.functions.len()
+			}
+			OP_JUMP | OP_JUMP_IF_FALSE | OP_JUMP_IF_TRUE => {
+				targets.push(arg);
+				code.len()
+			}
+			_ => {
+				usize::MAX
+			}
+		};
+		if arg >= limit {
+			return Err(format!("operand {} of {} at {} is out of range", arg, OPCODES[op as usize].0, ip))
+		}
+		starts.insert(ip);
+		last = Some(op);
+		ip = next;
+	}
+	if last != Some(OP_RETURN) {
+		return Err(format!("code doesn't end with a return"))
+	}
+	targets.extend(function.entries.iter().map(|e| *e as usize));
+	if let Some(t) = targets.into_iter().find(|t| !starts.contains(t)) {
+		return Err(format!("jump to {}, which is not the start of an instruction", t))
+	}
+	//Every path to an instruction must leave the same number of values
+	//above the frame's slots, and no instruction may take more than that
+	let mut heights = HashMap::new();
+	let mut pending: Vec<(usize, usize)> = function.entries.iter().map(|e| (*e as usize, 0)).collect();
+	while let Some((ip, height)) = pending.pop() {
+		match heights.insert(ip, height) {
+			Some(h) if h == height => {
+				continue;
+			}
+			Some(_) => {
+				return Err(format!("paths reaching {} leave different numbers of values on the stack", ip))
+			}
+			None => {}
+		}
+		let (op, arg, next) = decode(code, ip);
+		let (takes, gives) = stack_effect(op, arg);
+		if height < takes {
+			return Err(format!("{} at {} takes more values than the stack holds", OPCODES[op as usize].0, ip))
+		}
+		let after = height - takes + gives;
+		match op {
+			OP_JUMP => {
+				pending.push((arg, after));
+			}
+			OP_JUMP_IF_FALSE | OP_JUMP_IF_TRUE => {
+				pending.push((arg, after));
+				pending.push((next, after));
+			}
+			//Tail calls either replace the frame or return what the callee gave
+			OP_RETURN | OP_TAIL_CALL => {}
+			_ => {
+				pending.push((next, after));
+			}
+		}
+	}
+	Ok(())
+}
+
+//How many values an instruction pops, and how many it then pushes
+fn stack_effect(op: u8, arg: usize) -> (usize, usize) {
+	match op {
+		OP_CONST | OP_NIL | OP_GET_LOCAL | OP_GET_UPVALUE | OP_GET_GLOBAL | OP_GET_ROOT | OP_EVAL | OP_CLOSURE => {
+			(0, 1)
+		}
+		OP_DUP => {
+			(1, 2)
+		}
+		OP_SET_LOCAL | OP_SET_UPVALUE | OP_SET_GLOBAL | OP_SET_ROOT | OP_DEFINE_GLOBAL => {
+			(1, 1)
+		}
+		OP_STORE_LOCAL | OP_POP | OP_JUMP_IF_FALSE | OP_JUMP_IF_TRUE | OP_RETURN => {
+			(1, 0)
+		}
+		OP_CALL | OP_TAIL_CALL => {
+			(arg + 1, 1)
+		}
+		OP_VECTOR | OP_MAP | OP_SET => {
+			(arg, 1)
+		}
+		_ => {
+			(0, 0)
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::env::tests::{assert_error, assert_value, read, run};
+
+	fn compile_source(source: &str) -> Vec<u8> {
+		let env = Rc::new(RefCell::new(ExecutionEnv::root()));
+		let functions: Vec<Rc<Function>> = read(source).unwrap().iter().map(|form| compile_toplevel(form, &env)).collect();
+		write_module(&functions).unwrap()
+	}
+
+	//Whether a function or one nested in it jumps backwards, so might never finish
+	fn loops(function: &Function) -> bool {
+		let mut ip = 0;
+		while ip < function.code.len() {
+			let (op, arg, next) = decode(&function.code, ip);
+			if matches!(op, OP_JUMP | OP_JUMP_IF_FALSE | OP_JUMP_IF_TRUE) && arg <= ip {
+				return true;
+			}
+			ip = next;
+		}
+		function.functions.iter().any(|f| loops(f))
+	}
+
+	//Any single damaged byte must give an error rather than a panic,
+	//either while the module is read or while it runs
+	#[test]
+	fn damaged_modules_never_panic() {
+		let bytes = compile_source("(define x [1 2]) (list x {1 2} #{3} (car (list 4 5)) (if x (cdr x) 2) (lambda (y) (+ x y)))");
+		for i in MAGIC.len()..bytes.len() {
+			for b in 0..=u8::MAX {
+				let mut damaged = bytes.clone();
+				damaged[i] = b;
+				if let Ok(functions) = read_module(&damaged) {
+					if !functions.iter().any(|f| loops(f)) {
+						let env = Rc::new(RefCell::new(ExecutionEnv::root()));
+						let _ = run_module(&functions, &env);
+					}
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn stack_underflow_is_rejected() {
+		let mut bytes = compile_source("(list 1 2)");
+		//The module ends with the code: get-global list, const 1, const 2, tail-call 2, return
+		let at = bytes.len() - 3;
+		assert_eq!((bytes[at], bytes[at + 1]), (OP_TAIL_CALL, 2));
+		bytes[at + 1] = 5;
+		let err = read_module(&bytes).unwrap_err();
+		assert!(err.contains("tail-call at 9 takes more values than the stack holds"), "{}", err);
+	}
+	#[test]
+	fn top_level_parameters_are_rejected() {
+		let function = Function {
+			name: format!("toplevel"),
+			params: vec![format!("x")],
+			arity: Arity { required: 1, optional: 0, rest: false },
+			entries: vec![0],
+			slots: 1,
+			code: vec![OP_GET_LOCAL, 0, 0, OP_RETURN],
+			constants: Vec::new(),
+			functions: Vec::new(),
+			captures: Vec::new(),
+		};
+		let bytes = write_module(&[Rc::new(function)]).unwrap();
+		assert_eq!(read_module(&bytes).unwrap_err(), "a top level function can't take arguments");
+	}
+
+	//A closure can capture slots of its enclosing function that it doesn't have itself
+	#[test]
+	fn captured_slots_belong_to_the_enclosing_function() {
+		let bytes = compile_source("(defn f () (let ((a 1) (b 2)) (lambda () b))) ((f))");
+		let functions = read_module(&bytes).unwrap();
+		let env = Rc::new(RefCell::new(ExecutionEnv::root()));
+		assert_eq!(run_module(&functions, &env).unwrap().write(), "2");
+	}
+
+	//The written value of definitions followed by uses, run by the tree-walker,
+	//with f compiled by compile, and compiled as a module
+	fn assert_runs_alike(definitions: &str, uses: &str, expected: &str) {
+		let source = format!("{} {}", definitions, uses);
+		let walked = run(&source).map(|v| v.write());
+		let compiled = run(&format!("{} (set! f (compile f)) {}", definitions, uses)).map(|v| v.write());
+		let env = Rc::new(RefCell::new(ExecutionEnv::root()));
+		let mut functions = Vec::new();
+		for form in read(&source).unwrap() {
+			functions.push(compile_toplevel(&expand::expand(&env, form).unwrap(), &env));
+		}
+		let module = read_module(&write_module(&functions).unwrap()).unwrap();
+		let env = Rc::new(RefCell::new(ExecutionEnv::root()));
+		let loaded = run_module(&module, &env).map(|v| v.write());
+		for result in [walked, compiled, loaded] {
+			match result {
+				Ok(v) => {
+					assert_eq!(v, expected, "running {}", source);
+				}
+				Err(e) => {
+					panic!("running {} failed: {}", source, e);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn assigned_captures_are_shared() {
+		assert_runs_alike("(defn f () (let ((n 0)) (lambda () (set! n (+ n 1)))))",
+			"(define c (f)) (define d (f)) (c) (c) (d) (list (c) (d))", "(3 2)");
+		assert_runs_alike("(defn f (x) (list (lambda () x) (lambda (v) (set! x v))))",
+			"(define p (f 1)) ((car (cdr p)) 5) ((car p))", "5");
+		assert_runs_alike("(defn f (x) (lambda () (lambda () (progn (set! x (+ x 1)) x))))",
+			"(define g (f 1)) (list ((g)) ((g)))", "(2 3)");
+		assert_runs_alike("(defn f (n) (let ((g (lambda () n))) (progn (set! n 2) (g))))", "(f 1)", "2");
+	}
+
+	//Each call and each time a let runs makes new bindings, which
+	//the closures made before keep
+	#[test]
+	fn closures_keep_the_bindings_they_captured() {
+		assert_runs_alike("(defn f (n acc) (if (= n 0) acc (let ((x n)) (f (- n 1) (cons (lambda () (progn (set! x (* x 10)) x)) acc)))))",
+			"(define fs (f 2 (list))) (list ((car fs)) ((car fs)) ((car (cdr fs))))", "(10 100 20)");
+	}
+
+	#[test]
+	fn letrec_binds_before_initializing() {
+		assert_runs_alike("(defn f (n) (letrec ((ev? (lambda (n) (if (= n 0) true (od? (- n 1)))))
+			(od? (lambda (n) (if (= n 0) false (ev? (- n 1))))))
+			(list (ev? n) (od? n))))", "(f 10)", "(true false)");
+		assert_runs_alike("(defn f (n) (letrec ((loop (lambda (i acc) (if (= i n) acc (loop (+ i 1) (cons (lambda () i) acc))))))
+			(loop 0 (list))))", "(define fs (f 3)) (list ((car fs)) ((car (cdr fs))))", "(2 1)");
+	}
+
+	#[test]
+	fn parameters_and_errors_match_the_tree_walker() {
+		assert_runs_alike("(defn f (a &optional (b (+ a 1)) &rest r) (list a b r))",
+			"(list (f 1) (f 1 5) (f 1 5 6 7) (try (f) (catch (e) (error-kind e))))", "((1 2 ()) (1 5 ()) (1 5 (6 7)) arity-error)");
+		assert_runs_alike("(defn f (x) (cond ((< x 0) 'negative) ((= x 0) 'zero) (else (and x (or false [x {x x} #{x}])))))",
+			"(list (f -1) (f 0) (f 2) (try (f \"a\") (catch (e) (error-kind e))))", "(negative zero [2 {2 2} #{2}] type-error)");
+	}
+
+	#[test]
+	fn compiled_recursion_calls_the_compiled_closure() {
+		assert_value("(defn fib (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))
+			(define fast (compile fib))
+			(set! fib nil)
+			(fast 15)", "610");
+	}
+
+	#[test]
+	fn keyword_parameters_are_not_compiled() {
+		assert_error("(defn f (&key a) a) (compile f)", ErrorKind::CompileError, "closures with &key parameters can't be compiled");
+	}
+}
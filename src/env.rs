@@ -1,141 +1,830 @@
+use crate::binlisp::{self, Closure};
+use crate::expand::{self, SyntaxRules};
+use crate::error::{locate, unwind, ErrorKind, LispError};
 use crate::liblisp::Literal;
 use crate::liblisp::{self, builtins_table};
 use std::collections::HashMap;
-use std::ops::Deref;
 use std::rc::Rc;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 
-#[derive(Debug,Clone)]
+//How much stack nested evaluations may use, measured from the outermost one
+//on the thread, unless the thread sets its own budget. Threads spawned by std
+//get 2MB, and the outermost evaluation may already be partway down the stack
+const DEFAULT_STACK_BUDGET: usize = 1 << 20;
+
+//Compiled calls that aren't tail calls keep their frames on the heap,
+//so they're limited by count instead
+pub const MAX_FRAMES: usize = 100_000;
+
+thread_local! {
+	//How many evaluations are in progress
+	static DEPTH: Cell<usize> = const { Cell::new(0) };
+	//Where the outermost evaluation's frame is, and the budget below it
+	static STACK: Cell<(usize, usize)> = const { Cell::new((0, DEFAULT_STACK_BUDGET)) };
+}
+
+//For threads made with a bigger stack than usual
+pub fn set_stack_budget(bytes: usize) {
+	STACK.with(|stack| stack.set((stack.get().0, bytes)));
+}
+
+pub fn recursion_error() -> LispError {
+	LispError::new(ErrorKind::RecursionError, format!("Calls nested too deeply for the stack"))
+}
+
+//Counts an evaluation in progress until it is dropped,
+//so the count comes back down however the evaluation ends
+struct Nested;
+
+impl Nested {
+	//Refuses an evaluation once those in progress have used the stack budget,
+	//which depends on frame sizes and so on the build rather than only on depth
+	fn enter() -> Result<Nested, LispError> {
+		let marker = 0u8;
+		let here = &marker as *const u8 as usize;
+		let depth = DEPTH.with(|depth| depth.get());
+		STACK.with(|stack| {
+			let (base, budget) = stack.get();
+			if depth == 0 {
+				stack.set((here, budget));
+			}
+			else if base.abs_diff(here) > budget {
+				return Err(recursion_error())
+			}
+			Ok(())
+		})?;
+		DEPTH.with(|d| d.set(depth + 1));
+		Ok(Nested)
+	}
+}
+
+impl Drop for Nested {
+	fn drop(&mut self) {
+		DEPTH.with(|depth| depth.set(depth.get() - 1));
+	}
+}
+
+#[derive(Clone)]
 pub enum Executable {
-	//BytecodeObject,
+	//A closure compiled to bytecode for the VM in binlisp
+	BytecodeObject(Rc<Closure>),
+	//Builtins receive their argument forms unevaluated
 	Builtin(i64),
-	LispClosure(Vec<String>, Literal),
+	//Builtins with a subform in tail position, like if and progn
+	SpecialForm(i64),
+	//Builtin procedures, called with evaluated arguments
+	Primitive(i64),
+	//Parameters, body and the environment the closure was defined in
+	LispClosure(Params, Literal, Rc<RefCell<ExecutionEnv>>),
+	//Like a closure, but receives its argument forms unevaluated
+	//and returns a form that is evaluated in place of the call
+	Macro(Params, Literal, Rc<RefCell<ExecutionEnv>>),
+	//A hygienic define-syntax macro
+	SyntaxRules(SyntaxRules),
+}
+
+//The captured environment usually contains the closure itself,
+//so it is left out to keep the output finite
+impl std::fmt::Debug for Executable {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Executable::BytecodeObject(closure) => {
+				write!(f, "BytecodeObject({})", closure.function.lambda_list())
+			}
+			Executable::Builtin(num) => {
+				write!(f, "Builtin({})", num)
+			}
+			Executable::SpecialForm(num) => {
+				write!(f, "SpecialForm({})", num)
+			}
+			Executable::Primitive(num) => {
+				write!(f, "Primitive({})", num)
+			}
+			Executable::LispClosure(args, body, _) => {
+				write!(f, "LispClosure({}, {:?})", args.print(), body)
+			}
+			Executable::Macro(args, body, _) => {
+				write!(f, "Macro({}, {:?})", args.print(), body)
+			}
+			Executable::SyntaxRules(rules) => {
+				write!(f, "{:?}", rules)
+			}
+		}
+	}
+}
+
+//What calling an Executable leaves for the evaluator: either the finished
+//result, or a form in tail position to evaluate next in place of the call
+pub enum Tail {
+	Done(Literal),
+	Eval(Rc<RefCell<ExecutionEnv>>, Literal),
 }
 
 impl Executable {
-	pub fn evaluate_with(&self,  env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Literal{
+	//Calls with the unevaluated argument forms of a call in env
+	pub fn step(&self, env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Tail, LispError> {
 		match self {
 			Executable::Builtin(num) => {
-				liblisp::get_builtins()[*num as usize](env, params)
+				Ok(Tail::Done(liblisp::get_builtins()[*num as usize](env, params)?))
 			},
-			Executable::LispClosure(args, body) => {
-				let defs = args.into_iter().zip(params).map(|(s,l)| (s.to_string(),l)).collect();
-				{
-				env.borrow_mut().add_binds(defs);
+			Executable::SpecialForm(num) => {
+				liblisp::get_special_forms()[*num as usize](env, params)
+			}
+			Executable::Primitive(num) => {
+				let args = ExecutionEnv::evaluate_all(&env, params)?;
+				Ok(Tail::Done(liblisp::get_primitives()[*num as usize](args)?))
+			}
+			Executable::BytecodeObject(closure) => {
+				let args = ExecutionEnv::evaluate_all(&env, params)?;
+				Ok(Tail::Done(binlisp::run(closure.clone(), args)?))
+			}
+			Executable::LispClosure(args, body, scope) => {
+				//Arguments are evaluated where the call happens,
+				//but bound in a fresh child of the closure's own scope
+				let values = ExecutionEnv::evaluate_all(&env, params)?;
+				Ok(Tail::Eval(args.bind(scope.clone(), values)?, body.clone()))
+			}
+			Executable::SyntaxRules(rules) => {
+				//Uses the expander pass didn't see, such as those of
+				//macros defined earlier in the same top level form
+				let form = Literal::List(std::iter::once(Literal::Atom(format!("_"), None)).chain(params).collect(), None);
+				Ok(Tail::Eval(env, expand::expand_use(rules, &form)?))
+			}
+			Executable::Macro(_, _, _) => {
+				Ok(Tail::Eval(env, self.expand(params)?))
+			}
+		}
+	}
+	//Calls with argument values that have already been evaluated,
+	//which is how the bytecode VM calls anything it didn't compile
+	pub fn apply(&self, env: Rc<RefCell<ExecutionEnv>>, args: Vec<Literal>) -> Result<Literal, LispError> {
+		match self {
+			Executable::Primitive(num) => {
+				liblisp::get_primitives()[*num as usize](args)
+			}
+			Executable::BytecodeObject(closure) => {
+				binlisp::run(closure.clone(), args)
+			}
+			Executable::LispClosure(params, body, scope) => {
+				let inner = params.bind(scope.clone(), args)?;
+				ExecutionEnv::evaluate(inner, body.clone()).map_err(|e| unwind(e, &params.name))
+			}
+			Executable::Macro(_, _, _) | Executable::SyntaxRules(_) => {
+				Err(LispError::new(ErrorKind::TypeError, format!("macros can't be applied to evaluated arguments")))
+			}
+			Executable::Builtin(_) | Executable::SpecialForm(_) => {
+				//These evaluate their own argument forms, so each value is quoted
+				let forms = args.into_iter().map(|a| Literal::List(vec![Literal::Atom(format!("quote"), None), a], None)).collect();
+				match self.step(env, forms)? {
+					Tail::Done(v) => {
+						Ok(v)
+					}
+					Tail::Eval(next_env, next) => {
+						ExecutionEnv::evaluate(next_env, next)
+					}
 				}
-				ExecutionEnv::evaluate(env.clone(), body.clone())			
+			}
+		}
+	}
+	//Runs a macro's body on the unevaluated forms it was called with
+	pub fn expand(&self, forms: Vec<Literal>) -> Result<Literal, LispError> {
+		match self {
+			Executable::Macro(args, body, scope) => {
+				let inner = args.bind(scope.clone(), forms)?;
+				ExecutionEnv::evaluate(inner, body.clone())
 			}
 			_ => {
-				Literal::Atom(format!("null"))
+				Err(LispError::new(ErrorKind::TypeError, format!("only macros can be expanded")))
 			}
 		}
 	}
 }
 
+//A closure or macro's lambda list, like
+//(host &optional (port 80) &rest more &key timeout)
+#[derive(Clone, Debug)]
+pub struct Params {
+	//Names the function in arity errors, lambda for anonymous ones
+	pub name: String,
+	pub required: Vec<String>,
+	//Optional and keyword parameters with the forms giving their defaults
+	pub optional: Vec<(String, Literal)>,
+	//Collects the arguments after the required and optional ones
+	pub rest: Option<String>,
+	pub keys: Vec<(String, Literal)>,
+}
+
+//Which part of a lambda list is being read
+#[derive(PartialEq)]
+enum Section {
+	Required,
+	Optional,
+	Rest,
+	AfterRest,
+	Key,
+}
+
+impl Params {
+	pub fn new(name: &str) -> Self {
+		Params {
+			name: name.to_string(),
+			required: Vec::new(),
+			optional: Vec::new(),
+			rest: None,
+			keys: Vec::new(),
+		}
+	}
+	//Reads the lambda list of a lambda, defn or defmacro form, where
+	//(a b . rest) is the same as (a b &rest rest) and &body as &rest
+	pub fn parse(list: &Literal, form: &str) -> Result<Params, LispError> {
+		let items = match list {
+			Literal::List(l, _) => {
+				l.clone()
+			}
+			Literal::Nil => {
+				Vec::new()
+			}
+			_ => {
+				return Err(LispError::new(ErrorKind::InputError, format!("form '{}' requires list of argument names", form)))
+			}
+		};
+		let mut params = Params::new("lambda");
+		let mut section = Section::Required;
+		for item in items {
+			let defaults = section == Section::Optional || section == Section::Key;
+			let (name, default) = match &item {
+				Literal::Atom(name, _) | Literal::Syntax(name, _, _) => {
+					(name.clone(), Literal::Nil)
+				}
+				Literal::List(l, _) if defaults && l.len() == 2 && matches!(l[0], Literal::Atom(_, _) | Literal::Syntax(_, _, _)) => {
+					(l[0].print(), l[1].clone())
+				}
+				_ => {
+					return Err(LispError::new(ErrorKind::InputError, format!("form '{}' has a malformed parameter {}", form, item.write())))
+				}
+			};
+			let next = match name.as_str() {
+				"&optional" if section == Section::Required => {
+					Some(Section::Optional)
+				}
+				"&rest" | "&body" | "." if section == Section::Required || section == Section::Optional => {
+					Some(Section::Rest)
+				}
+				"&key" if section != Section::Rest && section != Section::Key => {
+					Some(Section::Key)
+				}
+				_ => {
+					None
+				}
+			};
+			if let Some(next) = next {
+				section = next;
+				continue;
+			}
+			if name.starts_with('&') || name == "." || section == Section::AfterRest {
+				return Err(LispError::new(ErrorKind::InputError, format!("form '{}' can't use {} here", form, name)))
+			}
+			if params.names().contains(&name) {
+				return Err(LispError::new(ErrorKind::InputError, format!("form '{}' names parameter {} twice", form, name)))
+			}
+			match section {
+				Section::Required => {
+					params.required.push(name);
+				}
+				Section::Optional => {
+					params.optional.push((name, default));
+				}
+				Section::Rest => {
+					params.rest = Some(name);
+					section = Section::AfterRest;
+				}
+				_ => {
+					params.keys.push((name, default));
+				}
+			}
+		}
+		if section == Section::Rest {
+			return Err(LispError::new(ErrorKind::InputError, format!("form '{}' needs a parameter name after &rest", form)))
+		}
+		Ok(params)
+	}
+	//Every name the parameters bind, in order
+	pub fn names(&self) -> Vec<String> {
+		let mut names = self.required.clone();
+		names.extend(self.optional.iter().map(|(n, _)| n.clone()));
+		names.extend(self.rest.clone());
+		names.extend(self.keys.iter().map(|(n, _)| n.clone()));
+		names
+	}
+	//The most arguments a call can pass, if there is a limit
+	pub fn max_args(&self) -> Option<usize> {
+		if self.rest.is_none() && self.keys.is_empty() {
+			Some(self.required.len() + self.optional.len())
+		}
+		else {
+			None
+		}
+	}
+	//The lambda list as it would be written
+	pub fn print(&self) -> String {
+		let with_defaults = |params: &[(String, Literal)]| params.iter().map(|(name, default)| {
+			match default {
+				Literal::Nil => {
+					name.clone()
+				}
+				d => {
+					format!("({} {})", name, d.write())
+				}
+			}
+		}).collect::<Vec<String>>();
+		let mut parts = self.required.clone();
+		if !self.optional.is_empty() {
+			parts.push(format!("&optional"));
+			parts.extend(with_defaults(&self.optional));
+		}
+		if let Some(rest) = &self.rest {
+			parts.push(format!("&rest"));
+			parts.push(rest.clone());
+		}
+		if !self.keys.is_empty() {
+			parts.push(format!("&key"));
+			parts.extend(with_defaults(&self.keys));
+		}
+		parts.join(" ")
+	}
+	//Binds argument values in a new scope inside scope. Keyword arguments
+	//follow the positional ones as :name value pairs, and the defaults of
+	//parameters left out are evaluated in the new scope, in order
+	pub fn bind(&self, scope: Rc<RefCell<ExecutionEnv>>, args: Vec<Literal>) -> Result<Rc<RefCell<ExecutionEnv>>, LispError> {
+		let max = self.max_args();
+		if args.len() < self.required.len() || max.is_some_and(|m| args.len() > m) {
+			return Err(arity_error(&self.name, self.required.len(), max, args.len()))
+		}
+		let mut args = args.into_iter();
+		let defs = self.required.iter().cloned().zip(args.by_ref()).collect();
+		let env = ExecutionEnv::extend(scope, defs);
+		for (name, default) in &self.optional {
+			let val = match args.next() {
+				Some(v) => {
+					v
+				}
+				None => {
+					ExecutionEnv::evaluate(env.clone(), default.clone())?
+				}
+			};
+			env.borrow_mut().define(name.clone(), val);
+		}
+		let rest: Vec<Literal> = args.collect();
+		if let Some(name) = &self.rest {
+			env.borrow_mut().define(name.clone(), Literal::List(rest.clone(), None));
+		}
+		if self.keys.is_empty() {
+			return Ok(env);
+		}
+		let mut given = HashMap::new();
+		let mut rest = rest.into_iter();
+		while let Some(arg) = rest.next() {
+			let key = match arg {
+				Literal::Keyword(k) if self.keys.iter().any(|(name, _)| *name == k) => {
+					k
+				}
+				Literal::Keyword(k) => {
+					return Err(LispError::new(ErrorKind::InputError, format!("{} has no keyword argument :{}", self.name, k)))
+				}
+				other => {
+					return Err(LispError::new(ErrorKind::InputError, format!("{} expected a keyword argument but got {}", self.name, other.write())))
+				}
+			};
+			match rest.next() {
+				Some(v) => {
+					//As in Common Lisp the first value given for a key is used
+					given.entry(key).or_insert(v);
+				}
+				None => {
+					return Err(LispError::new(ErrorKind::InputError, format!("keyword argument :{} has no value", key)))
+				}
+			}
+		}
+		for (name, default) in &self.keys {
+			let val = match given.remove(name) {
+				Some(v) => {
+					v
+				}
+				None => {
+					ExecutionEnv::evaluate(env.clone(), default.clone())?
+				}
+			};
+			env.borrow_mut().define(name.clone(), val);
+		}
+		Ok(env)
+	}
+}
+
+//The error for calling a function with the wrong number of arguments,
+//max is None when there is no upper limit
+pub fn arity_error(name: &str, min: usize, max: Option<usize>, got: usize) -> LispError {
+	let plural = |n: usize| if n == 1 { format!("{} argument", n) } else { format!("{} arguments", n) };
+	let expected = match max {
+		Some(max) if max == min => {
+			plural(min)
+		}
+		Some(max) => {
+			format!("{} to {}", min, plural(max))
+		}
+		None => {
+			format!("at least {}", plural(min))
+		}
+	};
+	LispError::new(ErrorKind::ArityError, format!("{} requires {} but got {}", name, expected, got))
+}
+
 #[derive(Clone, Debug)]
 pub struct ExecutionEnv {
 	permission: i64,
 	parent: Option<Rc<RefCell<ExecutionEnv>>>,
+	//Functions live alongside every other value as Literal::Func
 	defined_vals: HashMap<String,Literal>,
-	defined_funcs: HashMap<String,Executable>
 }
 
 impl ExecutionEnv {
-	pub fn new(permnum: i64, par: Rc<RefCell<ExecutionEnv>>, vals: HashMap<String, Literal>, funcs: HashMap<String, Executable>) -> Self {
+	pub fn new(permnum: i64, par: Rc<RefCell<ExecutionEnv>>, vals: HashMap<String, Literal>) -> Self {
 		Self {
 			permission: permnum,
 			parent: Some(par),
 			defined_vals: vals,
-			defined_funcs: funcs
 		}
 	}
 	pub fn root() -> Self {
 		Self {
 			permission: 0,
 			parent: None,
-			defined_vals: HashMap::new(),
-			defined_funcs: builtins_table()
+			defined_vals: builtins_table().into_iter().map(|(name, exec)| (name, Literal::Func(Rc::new(exec)))).collect(),
+		}
+	}
+	//Creates a new scope nested inside env holding the given bindings
+	pub fn extend(env: Rc<RefCell<ExecutionEnv>>, vals: HashMap<String, Literal>) -> Rc<RefCell<ExecutionEnv>> {
+		let permission = env.borrow().permission;
+		Rc::new(RefCell::new(ExecutionEnv::new(permission, env, vals)))
+	}
+	//The outermost scope, where top level definitions live
+	pub fn global(env: &Rc<RefCell<ExecutionEnv>>) -> Rc<RefCell<ExecutionEnv>> {
+		let mut env = env.clone();
+		loop {
+			let parent = env.borrow().parent.clone();
+			match parent {
+				Some(p) => {
+					env = p;
+				}
+				None => {
+					return env
+				}
+			}
 		}
 	}
 	pub fn add_function(&mut self,func_name: String,func: Executable) {
-		self.defined_funcs.insert(func_name, func);
+		self.define(func_name, Literal::Func(Rc::new(func)));
 	}
-	pub fn get_parent(&self) -> ExecutionEnv {
-		self.parent.as_ref().unwrap().deref().clone().into_inner()
+	//Binds a name in this scope, shadowing any outer binding
+	pub fn define(&mut self, name: String, val: Literal) {
+		self.defined_vals.insert(name, val);
 	}
-	pub fn add_binds(&mut self, binds: HashMap<String, Literal>) {
-		self.defined_vals.extend(binds);
+	//Rebinds the nearest existing binding of a name
+	pub fn set(&mut self, name: &str, val: Literal) -> Result<(), LispError> {
+		match self.defined_vals.get_mut(name) {
+			Some(v) => {
+				*v = val;
+				Ok(())
+			}
+			None => {
+				match &self.parent {
+					Some(p) => {
+						p.borrow_mut().set(name, val)
+					}
+					None => {
+						Err(LispError::new(ErrorKind::UnboundSymbol, format!("{} is not a bound symbol", name)))
+					}
+				}
+			}
+		}
 	}
-	pub fn lookup_function(&self, val: &liblisp::Literal) -> Result<Executable, Literal> {
-		match val {
-			Literal::Atom(s) => {
-				match self.defined_funcs.get(s) {
-					Some(e) => {
-						Ok(e.clone())
+	//Returns the macro a form invokes, if its head names one
+	pub fn macro_for(&self, form: &Literal) -> Option<Rc<Executable>> {
+		if let Literal::List(l, _) = form {
+			if let Some(Literal::Atom(name, _)) = l.first() {
+				if let Ok(Literal::Func(exec)) = self.lookup_value(name) {
+					if let Executable::Macro(_, _, _) = exec.as_ref() {
+						return Some(exec);
+					}
+				}
+			}
+		}
+		None
+	}
+	//Resolves a symbol's value, searching enclosing scopes outwards
+	pub fn lookup_value(&self, name: &str) -> Result<Literal, LispError> {
+		match self.defined_vals.get(name) {
+			Some(v) => {
+				Ok(v.clone())
+			}
+			None => {
+				match &self.parent {
+					Some(p) => {
+						p.borrow().lookup_value(name)
 					}
 					None => {
-						match self.parent.clone() {
-							Some(_) => {
-								self.get_parent().lookup_function(val)
-							}
-							None => {
-								Err(Literal::Err(format!("{:?} is not a recognized function name", val), 3))
-							}
-						}
+						Err(LispError::new(ErrorKind::UnboundSymbol, format!("{} is not a bound symbol", name)))
 					}
 				}
-			},
+			}
+		}
+	}
+	//An error leaving a call is marked with the closure that was running,
+	//the last one entered since tail calls replace their caller
+	pub fn evaluate(env: Rc<RefCell<ExecutionEnv>>, val: liblisp::Literal) -> Result<Literal, LispError> {
+		let _nested = Nested::enter()?;
+		let mut frame = None;
+		let result = ExecutionEnv::evaluate_in(env, val, &mut frame);
+		match frame.as_deref() {
+			Some(Executable::LispClosure(params, _, _)) => {
+				result.map_err(|e| unwind(e, &params.name))
+			}
 			_ => {
-				Err(Literal::Err(format!("{} is not a recognized function name", val.print()), 3))
+				result
 			}
 		}
 	}
-	pub fn evaluate(env: Rc<RefCell<ExecutionEnv>>, val: liblisp::Literal) -> liblisp::Literal {
-		match val {
-			Literal::String(s) => {
-				Literal::String(s)
-			},
-			Literal::Num(_) => {
-				val
-			},
-			Literal::Atom(s) => {
-				env.deref().clone().into_inner().defined_vals[&s].clone()
-			}
-			//For this to work:
-			//function definition form /// closure form MUST return defined name of function
-			Literal::List(l) => {
-				let mut list = l.into_iter();
-				let first_entry = list.next();
-				let func: Result<Executable, Literal>;
-				match first_entry {
-					Some(mut name) => {
-						match &name {
-							Literal::List(_) => {
-								name = ExecutionEnv::evaluate(env.clone(), name);
-							}
-							_ => {
-
+	//Evaluates forms in order, stopping at the first error
+	pub fn evaluate_all(env: &Rc<RefCell<ExecutionEnv>>, forms: Vec<Literal>) -> Result<Vec<Literal>, LispError> {
+		forms.into_iter().map(|form| ExecutionEnv::evaluate(env.clone(), form)).collect()
+	}
+	//Tail calls replace env and val and go round the loop again
+	//instead of recursing, so they run in constant Rust stack
+	fn evaluate_in(env: Rc<RefCell<ExecutionEnv>>, val: liblisp::Literal, frame: &mut Option<Rc<Executable>>) -> Result<Literal, LispError> {
+		let mut env = env;
+		let mut val = val;
+		loop {
+			match val {
+				Literal::String(s) => {
+					return Ok(Literal::String(s))
+				},
+				Literal::Num(_) | Literal::Keyword(_) | Literal::Bool(_) | Literal::Nil | Literal::Func(_) | Literal::Err(_) => {
+					return Ok(val)
+				},
+				Literal::Atom(s, span) => {
+					return env.borrow().lookup_value(&s).map_err(|e| locate(e, span))
+				}
+				//Named by a syntax-rules template, so looked up where the macro was defined
+				Literal::Syntax(s, _, scope) => {
+					return scope.0.borrow().lookup_value(&s)
+				}
+				//The head of a list is evaluated like any other value,
+				//so named functions, lambdas and calls returning functions all work.
+				//An error leaving the form is tagged with its location
+				Literal::List(l, span) => {
+					let mut list = l.into_iter();
+					let first_entry = list.next();
+					match first_entry {
+						Some(head) => {
+							let func = ExecutionEnv::evaluate(env.clone(), head.clone()).map_err(|e| locate(e, span))?;
+							match func {
+								Literal::Func(exec) => {
+									match exec.step(env.clone(), list.collect()).map_err(|e| locate(e, span))? {
+										Tail::Done(v) => {
+											return Ok(v)
+										}
+										Tail::Eval(next_env, next) => {
+											if let Executable::LispClosure(_, _, _) = exec.as_ref() {
+												*frame = Some(exec);
+											}
+											env = next_env;
+											val = next;
+										}
+									}
+								}
+								_ => {
+									return Err(locate(LispError::new(ErrorKind::TypeError, format!("{} is not a function", head.print())), span))
+								}
 							}
 						}
-						let ref_to_env = env.deref().clone();
-						func = ref_to_env.into_inner().lookup_function(&name);
-						match func {
-							Ok(exec) => {
-								exec.evaluate_with(env.clone(), list.collect())
-							}
-							Err(e) => {
-								e
-							}
+						None => {
+							return Ok(Literal::Nil)
 						}
 					}
-					None => {
-						Literal::Atom(format!("null"))
-					}
+				}
+				//Collection literals evaluate each form inside them
+				Literal::Vector(_, _) | Literal::Map(_, _) | Literal::Set(_, _) => {
+					let values = ExecutionEnv::evaluate_all(&env, val.collection_forms().unwrap()).map_err(|e| locate(e, val.span()))?;
+					return Ok(val.rebuild_collection(values))
 				}
 			}
-			Literal::Err(s,c ) => {
-				Literal::Err(s, c)
+		}
+	}
+}
+
+#[cfg(test)]
+pub mod tests {
+	use super::*;
+	use crate::expand;
+	use crate::lex::Lexer;
+	use crate::reader::Parser;
+
+	pub fn read(source: &str) -> Result<Vec<Literal>, LispError> {
+		let mut lexer = Lexer::from_string(source.to_string());
+		lexer.tokenize()?;
+		lexer.substitute();
+		let mut parser = Parser::from_tokenizer(lexer);
+		parser.parse()?;
+		Ok(parser.out())
+	}
+
+	//Evaluates every form of source in a fresh environment, giving the last value
+	pub fn run(source: &str) -> Result<Literal, LispError> {
+		let env = Rc::new(RefCell::new(ExecutionEnv::root()));
+		let mut last = Literal::Nil;
+		for form in read(source)? {
+			last = ExecutionEnv::evaluate(env.clone(), expand::expand(&env, form)?)?;
+		}
+		Ok(last)
+	}
+
+	//Checks the written form of the value source evaluates to
+	pub fn assert_value(source: &str, expected: &str) {
+		match run(source) {
+			Ok(v) => {
+				assert_eq!(v.write(), expected, "evaluating {}", source);
+			}
+			Err(e) => {
+				panic!("evaluating {} failed: {}", source, e);
 			}
 		}
 	}
+
+	//Checks the kind and message of the error source raises
+	pub fn assert_error(source: &str, kind: ErrorKind, message: &str) {
+		let err = run(source).expect_err(source);
+		assert_eq!((err.kind, err.message.as_str()), (kind, message), "evaluating {}", source);
+	}
+
+	//Each recursion runs a hundred thousand times, far deeper than the stack
+	//budget allows if the tail call weren't run in the evaluator's loop
+	fn assert_counts_down(tail: &str) {
+		let source = format!("(defn count (n) (if (= n 0) 'done {})) (count 100000)", tail);
+		assert_value(&source, "done");
+	}
+
+	#[test]
+	fn tail_call_in_if() {
+		assert_counts_down("(count (- n 1))");
+	}
+
+	#[test]
+	fn tail_call_in_cond() {
+		assert_value("(defn count (n) (cond ((= n 0) 'done) (else (count (- n 1))))) (count 100000)", "done");
+	}
+
+	#[test]
+	fn tail_call_in_when() {
+		assert_counts_down("(when (> n 0) (count (- n 1)))");
+	}
+
+	#[test]
+	fn tail_call_in_let() {
+		assert_counts_down("(let ((m (- n 1))) (count m))");
+	}
+
+	#[test]
+	fn tail_call_in_and() {
+		assert_counts_down("(and true (count (- n 1)))");
+	}
+
+	#[test]
+	fn tail_call_in_progn() {
+		assert_counts_down("(progn n (count (- n 1)))");
+	}
+
+	#[test]
+	fn mutual_tail_calls() {
+		assert_value("(define even? (lambda (n) (if (= n 0) true (odd? (- n 1)))))
+			(define odd? (lambda (n) (if (= n 0) false (even? (- n 1)))))
+			(even? 100000)", "true");
+	}
+
+	#[test]
+	fn compiled_tail_call() {
+		let source = "(defn count (n) (if (= n 0) 'done (count (- n 1))))
+			(set! count (compile count))
+			(list (count 100000) (disassemble count))";
+		match run(source) {
+			Ok(Literal::List(l, _)) => {
+				assert_eq!(l[0].write(), "done");
+				assert!(l[1].print().contains("tail-call"), "{}", l[1].print());
+			}
+			other => {
+				panic!("compiled count gave {:?}", other);
+			}
+		}
+	}
+
+	#[test]
+	fn keyword_arguments_with_special_characters() {
+		assert_value("(defn connect (host &key max-retries (verbose? false)) (list host max-retries verbose?))
+			(connect \"db\" :max-retries 3 :verbose? true)", "(\"db\" 3 true)");
+	}
+
+	//The line and column an error raised by source is reported at
+	pub fn error_at(source: &str) -> (usize, usize) {
+		let span = run(source).expect_err(source).span.expect("error has no location");
+		(span.start.line, span.start.column)
+	}
+
+	#[test]
+	fn errors_point_at_symbols_and_collections() {
+		assert_eq!(error_at("(list 1 -x)"), (1, 9));
+		assert_eq!(error_at("_1"), (1, 1));
+		assert_eq!(error_at("#{1 zz}"), (1, 5));
+		assert_eq!(error_at("(define v 1)\n[1 {:a (car v)}]"), (2, 8));
+	}
+
+	const DEEP: &str = "(defn f (n) (if (= n 0) 0 (+ 1 (f (- n 1)))))";
+
+	//The kind of error each program gives, run on a new thread
+	fn kinds_on_thread(stack: usize, budget: Option<usize>, programs: Vec<String>) -> Vec<Option<ErrorKind>> {
+		std::thread::Builder::new().stack_size(stack).spawn(move || {
+			if let Some(budget) = budget {
+				set_stack_budget(budget);
+			}
+			programs.iter().map(|source| run(source).err().map(|e| e.kind)).collect()
+		}).unwrap().join().unwrap()
+	}
+
+	//On an ordinary thread, as anything embedding the evaluator would use
+	#[test]
+	fn deep_recursion_is_an_error() {
+		let programs = vec![
+			format!("{} (f 50)", DEEP),
+			format!("{} (f 100000)", DEEP),
+			format!("{} (set! f (compile f)) (f 200000)", DEEP),
+			format!("{} (try (f 100000) (catch (e) nil)) (f 50)", DEEP),
+		];
+		let expected = vec![None, Some(ErrorKind::RecursionError), Some(ErrorKind::RecursionError), None];
+		assert_eq!(kinds_on_thread(2 << 20, None, programs.clone()), expected);
+		assert_eq!(kinds_on_thread(8 << 20, None, programs), expected);
+	}
+
+	#[test]
+	fn the_interpreter_thread_recurses_deeper() {
+		let programs = vec![format!("{} (f 10000)", DEEP), format!("{} (f 1000000)", DEEP)];
+		let kinds = kinds_on_thread(crate::STACK_SIZE, Some(crate::STACK_BUDGET), programs);
+		assert_eq!(kinds, vec![None, Some(ErrorKind::RecursionError)]);
+	}
+
+
+	#[test]
+	fn parameters_are_bound_in_each_call() {
+		assert_value("(define x 1) (defn f (x) (* x 10)) (list (f 5) x)", "(50 1)");
+		assert_value("(defn fact (n) (if (= n 0) 1 (* n (fact (- n 1))))) (fact 10)", "3628800");
+		assert_value("(defn outer (x) (progn (defn inner (x) (* x 2)) (+ x (inner (+ x 1))))) (outer 3)", "11");
+		assert_error("(defn outer (x) (progn (defn inner (x) x) (inner x))) (outer 3) (inner 1)", ErrorKind::UnboundSymbol, "inner is not a bound symbol");
+	}
+
+	#[test]
+	fn closures_see_where_they_were_defined() {
+		assert_value("(defn make-adder (n) (lambda (x) (+ x n)))
+			(define add2 (make-adder 2))
+			(define add5 (make-adder 5))
+			(list (add2 1) (add5 1))", "(3 6)");
+		assert_value("(define x 1) (defn f () x) (let ((x 2)) (f))", "1");
+	}
+
+	#[test]
+	fn names_resolve_through_enclosing_scopes() {
+		assert_value("(define a 1) (let ((b 2)) (let ((c 3)) (+ a b c)))", "6");
+		assert_value("(define n 5) (defn inc! () (set! n (+ n 1))) (inc!) n", "6");
+	}
+
+	#[test]
+	fn unbound_symbols_are_errors() {
+		assert_error("(defn f (y) y) (f 1) y", ErrorKind::UnboundSymbol, "y is not a bound symbol");
+		assert_error("(zz 1)", ErrorKind::UnboundSymbol, "zz is not a bound symbol");
+		assert_error("(set! zz 1)", ErrorKind::UnboundSymbol, "zz is not a bound symbol");
+		assert_eq!(error_at("(defn f ()\n  (g))\n(f)"), (2, 4));
+	}
+
+	#[test]
+	fn optional_and_rest_parameters() {
+		assert_value("(defn f (a &optional (b 2) &rest r) (list a b r)) (list (f 1) (f 1 5) (f 1 5 6 7))", "((1 2 ()) (1 5 ()) (1 5 (6 7)))");
+		assert_value("(defn f (&optional (x 1) (y (+ x 1)) z) (list x y z)) (f)", "(1 2 nil)");
+		assert_value("(defn f (a . r) r) (f 1 2 3)", "(2 3)");
+		assert_error("(defn f (a &rest r s) a)", ErrorKind::InputError, "form 'defn' can't use s here");
+		assert_error("(defn f (a a) a)", ErrorKind::InputError, "form 'defn' names parameter a twice");
+	}
+
+	#[test]
+	fn arity_errors_name_the_function_and_counts() {
+		assert_error("(defn f (a b) a) (f 1)", ErrorKind::ArityError, "f requires 2 arguments but got 1");
+		assert_error("(defn f (a &optional b) a) (f 1 2 3)", ErrorKind::ArityError, "f requires 1 to 2 arguments but got 3");
+		assert_error("(defn f (a &rest r) a) (f)", ErrorKind::ArityError, "f requires at least 1 argument but got 0");
+		assert_error("((lambda (a) a))", ErrorKind::ArityError, "lambda requires 1 argument but got 0");
+		assert_error("(defn f (a &optional b) a) (set! f (compile f)) (f 1 2 3)", ErrorKind::ArityError, "f requires 1 to 2 arguments but got 3");
+	}
 }
@@ -0,0 +1,275 @@
+use crate::lex::{Span, SyntaxError};
+use crate::liblisp::Literal;
+use std::fmt;
+use std::rc::Rc;
+use std::ops::{Deref, DerefMut};
+
+//Every kind of error the interpreter raises. The discriminant is the
+//error's code, and a script that fails exits with its absolute value,
+//so host applications can match on either
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+	//Source text that can't be read
+	ParseError = -1,
+	UnboundSymbol = 3,
+	//A value of the wrong type, including calling something that isn't a function
+	TypeError = -4,
+	//A builtin used with the wrong number or shape of arguments
+	InputError = -5,
+	CompileError = -6,
+	ArithmeticError = -7,
+	//A closure called with the wrong number of arguments
+	ArityError = -8,
+	//A file that can't be read or written
+	IoError = -9,
+	//A malformed macro definition or a use no pattern matches
+	SyntaxError = -10,
+	//An error raised by the program itself
+	UserError = -11,
+	//Evaluations nested deeper than the interpreter's stack allows
+	RecursionError = -12,
+}
+
+pub const ERROR_KINDS: [ErrorKind; 11] = [
+	ErrorKind::ParseError,
+	ErrorKind::UnboundSymbol,
+	ErrorKind::TypeError,
+	ErrorKind::InputError,
+	ErrorKind::CompileError,
+	ErrorKind::ArithmeticError,
+	ErrorKind::ArityError,
+	ErrorKind::IoError,
+	ErrorKind::SyntaxError,
+	ErrorKind::UserError,
+	ErrorKind::RecursionError,
+];
+
+impl ErrorKind {
+	pub fn code(self) -> i64 {
+		self as i64
+	}
+	//How the kind is named when an error is printed
+	pub fn title(self) -> &'static str {
+		match self {
+			ErrorKind::ParseError => {
+				"Parse Error"
+			}
+			ErrorKind::UnboundSymbol => {
+				"Name Error"
+			}
+			ErrorKind::TypeError => {
+				"Type Error"
+			}
+			ErrorKind::InputError => {
+				"Input Error"
+			}
+			ErrorKind::CompileError => {
+				"Compile Error"
+			}
+			ErrorKind::ArithmeticError => {
+				"Arithmetic Error"
+			}
+			ErrorKind::ArityError => {
+				"Arity Error"
+			}
+			ErrorKind::IoError => {
+				"IO Error"
+			}
+			ErrorKind::SyntaxError => {
+				"Syntax Error"
+			}
+			ErrorKind::UserError => {
+				"Error"
+			}
+			ErrorKind::RecursionError => {
+				"Recursion Error"
+			}
+		}
+	}
+	//The symbol naming the kind in Lisp, as error-kind returns
+	//and catch and handler-bind clauses match
+	pub fn name(self) -> &'static str {
+		match self {
+			ErrorKind::ParseError => {
+				"parse-error"
+			}
+			ErrorKind::UnboundSymbol => {
+				"name-error"
+			}
+			ErrorKind::TypeError => {
+				"type-error"
+			}
+			ErrorKind::InputError => {
+				"input-error"
+			}
+			ErrorKind::CompileError => {
+				"compile-error"
+			}
+			ErrorKind::ArithmeticError => {
+				"arithmetic-error"
+			}
+			ErrorKind::ArityError => {
+				"arity-error"
+			}
+			ErrorKind::IoError => {
+				"io-error"
+			}
+			ErrorKind::SyntaxError => {
+				"syntax-error"
+			}
+			ErrorKind::UserError => {
+				"user-error"
+			}
+			ErrorKind::RecursionError => {
+				"recursion-error"
+			}
+		}
+	}
+	pub fn from_name(name: &str) -> Option<ErrorKind> {
+		ERROR_KINDS.iter().copied().find(|kind| kind.name() == name)
+	}
+}
+
+//Boxed so a result holding an error is no bigger than one holding a value
+#[derive(Clone, Debug)]
+pub struct LispError(Box<ErrorInfo>);
+
+#[derive(Clone, Debug)]
+pub struct ErrorInfo {
+	pub kind: ErrorKind,
+	pub message: String,
+	//The values the error is about, printed after the message
+	pub irritants: Vec<Literal>,
+	//The innermost form read from source that the error passed through
+	pub span: Option<Span>,
+	//The closures that were running when it was raised, innermost first
+	pub stack: Vec<String>,
+	//The value given to raise when it wasn't an error, which catch hands back
+	pub payload: Option<Literal>,
+	//Set when this isn't an error at all but invoke-restart unwinding to
+	//the restart-case that established the restart, with its arguments
+	pub restart: Option<(String, Vec<Literal>)>,
+	//Whether the handler-bind handlers in force have already been run for it
+	pub signaled: bool,
+}
+
+impl LispError {
+	pub fn new(kind: ErrorKind, message: String) -> Self {
+		LispError(Box::new(ErrorInfo {
+			kind,
+			message,
+			irritants: Vec::new(),
+			span: None,
+			stack: Vec::new(),
+			payload: None,
+			restart: None,
+			signaled: false,
+		}))
+	}
+	//An error about the given values, which are printed after the message
+	pub fn about(kind: ErrorKind, message: String, irritants: Vec<Literal>) -> Self {
+		let mut err = LispError::new(kind, message);
+		err.irritants = irritants;
+		err
+	}
+	//What raise does with a value that isn't already an error
+	pub fn raised(payload: Literal) -> Self {
+		let mut err = LispError::about(ErrorKind::UserError, format!("uncaught raise"), vec![payload.clone()]);
+		err.payload = Some(payload);
+		err
+	}
+	pub fn code(&self) -> i64 {
+		self.kind.code()
+	}
+	//The value a handler receives: whatever was raised, or the error itself
+	pub fn condition(&self) -> Literal {
+		match &self.payload {
+			Some(payload) => {
+				payload.clone()
+			}
+			None => {
+				Literal::Err(Rc::new(self.clone()))
+			}
+		}
+	}
+}
+
+impl Deref for LispError {
+	type Target = ErrorInfo;
+	fn deref(&self) -> &ErrorInfo {
+		&self.0
+	}
+}
+
+impl DerefMut for LispError {
+	fn deref_mut(&mut self) -> &mut ErrorInfo {
+		&mut self.0
+	}
+}
+
+impl From<SyntaxError> for LispError {
+	fn from(e: SyntaxError) -> Self {
+		let mut err = LispError::new(ErrorKind::ParseError, e.message);
+		err.span = Some(Span::new(e.position, e.position));
+		err
+	}
+}
+
+impl fmt::Display for LispError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}: {}", self.kind.title(), self.message)?;
+		if !self.irritants.is_empty() {
+			let irritants: Vec<String> = self.irritants.iter().map(|i| i.write()).collect();
+			write!(f, ": {}", irritants.join(" "))?;
+		}
+		if let Some(span) = self.span {
+			write!(f, " at {}", span)?;
+		}
+		Ok(())
+	}
+}
+
+//Records where an error passed through on its way out: the span of the
+//innermost source form, and each closure it unwound
+pub fn locate(mut err: LispError, span: Option<Span>) -> LispError {
+	if err.span.is_none() {
+		err.span = span;
+	}
+	err
+}
+
+pub fn unwind(mut err: LispError, function: &str) -> LispError {
+	err.stack.push(function.to_string());
+	err
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::env::tests::run;
+
+	#[test]
+	fn kinds_have_distinct_codes_and_names() {
+		for (i, kind) in ERROR_KINDS.iter().enumerate() {
+			assert_eq!(ErrorKind::from_name(kind.name()), Some(*kind));
+			assert!(ERROR_KINDS[..i].iter().all(|k| k.code() != kind.code()), "{:?}", kind);
+		}
+		assert_eq!(ErrorKind::from_name("no-such-error"), None);
+	}
+
+	#[test]
+	fn errors_carry_irritants_and_their_location() {
+		let err = run("(list 1\n  (error \"bad thing\" 1 \"two\"))").unwrap_err();
+		assert_eq!((err.kind, err.code()), (ErrorKind::UserError, -11));
+		assert_eq!(err.to_string(), "Error: bad thing: 1 \"two\" at line 2, column 3");
+	}
+
+	//Innermost first, as the REPL prints them
+	#[test]
+	fn errors_record_the_closures_they_unwind() {
+		let err = run("(defn g (x) (car x)) (defn f () (list (g 1))) (f)").unwrap_err();
+		assert_eq!(err.kind, ErrorKind::TypeError);
+		assert_eq!(err.stack, ["g", "f"]);
+		assert_eq!(err.to_string(), "Type Error: Operation 'car' requires a list: 1 at line 1, column 13");
+	}
+}
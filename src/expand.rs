@@ -0,0 +1,733 @@
+use crate::env::{Executable, ExecutionEnv};
+use crate::error::{ErrorKind, LispError};
+use crate::lex::Span;
+use crate::liblisp::Literal;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+//Hygienic expansion works in two steps:
+//transcribe rewrites a macro use with its template, wrapping every identifier
+//the template introduces in a Literal::Syntax carrying a fresh mark and the
+//scope the macro was defined in, then resolve gives marked identifiers bound
+//by the expansion itself fresh names. The remaining ones are free in the
+//template and stay marked, so they're looked up in the macro's scope however
+//the use site binds the same name. Only syntax like if and else, which forms
+//recognize by name, becomes plain atoms.
+//Forms from the macro use are substituted unmarked, so they can never refer
+//to a binding the template introduced.
+
+thread_local! {
+  //Source of fresh marks and renamed identifiers
+  static COUNTER: Cell<usize> = const { Cell::new(0) };
+}
+
+fn fresh() -> usize {
+  COUNTER.with(|c| {
+    c.set(c.get() + 1);
+    c.get()
+  })
+}
+
+//A define-syntax macro, its rules are tried in order until a pattern matches
+#[derive(Clone, Debug)]
+pub struct SyntaxRules {
+  literals: Vec<String>,
+  rules: Vec<(Literal, Literal)>,
+  scope: Scope,
+}
+
+//The scope a macro was defined in, where the free identifiers of its templates are looked up
+#[derive(Clone)]
+pub struct Scope(pub Rc<RefCell<ExecutionEnv>>);
+
+//The environment usually contains the macro itself, so it is left out
+impl fmt::Debug for Scope {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "#<scope>")
+  }
+}
+
+//Names forms recognize inside themselves rather than look up
+const AUXILIARY: [&str; 4] = ["else", "catch", "finally", "."];
+
+//What a pattern variable matched, variables under an ellipsis match a sequence
+#[derive(Clone, Debug)]
+enum Binding {
+  One(Literal),
+  Many(Vec<Binding>),
+}
+
+type Bindings = HashMap<String, Binding>;
+
+const ELLIPSIS: &str = "...";
+
+//The name of an identifier, marked or not
+fn symbol_name(l: &Literal) -> Option<&str> {
+  match l {
+    Literal::Atom(s, _) => {
+      Some(s.as_str())
+    }
+    Literal::Syntax(s, _, _) => {
+      Some(s.as_str())
+    }
+    _ => {
+      None
+    }
+  }
+}
+
+fn is_ellipsis(l: &Literal) -> bool {
+  symbol_name(l) == Some(ELLIPSIS)
+}
+
+fn syntax_error(msg: String) -> LispError {
+  LispError::new(ErrorKind::SyntaxError, msg)
+}
+
+impl SyntaxRules {
+  //Reads a (syntax-rules (literals...) (pattern template)...) form
+  pub fn from_spec(spec: Literal, env: &Rc<RefCell<ExecutionEnv>>) -> Result<Self, LispError> {
+    let items = match spec {
+      Literal::List(l, _) => {
+        l
+      }
+      _ => {
+        return Err(syntax_error(format!("define-syntax requires a syntax-rules form")))
+      }
+    };
+    let mut items = items.into_iter();
+    match items.next() {
+      Some(head) if symbol_name(&head) == Some("syntax-rules") => {}
+      _ => {
+        return Err(syntax_error(format!("define-syntax requires a syntax-rules form")))
+      }
+    }
+    let literals = match items.next() {
+      Some(Literal::List(l, _)) => {
+        let mut names = Vec::new();
+        for lit in l {
+          match symbol_name(&lit) {
+            Some(name) => {
+              names.push(name.to_string());
+            }
+            None => {
+              return Err(syntax_error(format!("syntax-rules literals must be symbols")))
+            }
+          }
+        }
+        names
+      }
+      Some(Literal::Nil) => {
+        Vec::new()
+      }
+      _ => {
+        return Err(syntax_error(format!("syntax-rules requires a list of literals")))
+      }
+    };
+    let mut rules = Vec::new();
+    for rule in items {
+      match rule {
+        Literal::List(r, _) if r.len() == 2 => {
+          let mut r = r.into_iter();
+          let pattern = r.next().unwrap();
+          if let Literal::List(_, _) = pattern {}
+          else {
+            return Err(syntax_error(format!("syntax-rules patterns must be lists")))
+          }
+          rules.push((pattern, r.next().unwrap()));
+        }
+        _ => {
+          return Err(syntax_error(format!("syntax-rules requires (pattern template) rules")))
+        }
+      }
+    }
+    Ok(SyntaxRules { literals, rules, scope: Scope(env.clone()) })
+  }
+  //Rewrites a macro use with the template of the first matching rule,
+  //the keyword position of the form and of each pattern is ignored
+  fn transcribe(&self, form: &Literal) -> Result<Literal, LispError> {
+    let args = match form {
+      Literal::List(l, _) if !l.is_empty() => {
+        &l[1..]
+      }
+      _ => {
+        return Err(syntax_error(format!("macro use must be a list")))
+      }
+    };
+    for (pattern, template) in self.rules.iter() {
+      if let Literal::List(p, _) = pattern {
+        let mut bindings = HashMap::new();
+        if !p.is_empty() && self.match_list(&p[1..], args, &mut bindings) {
+          return instantiate(template, &bindings, fresh(), &self.scope);
+        }
+      }
+    }
+    Err(syntax_error(format!("no syntax-rules pattern matches {}", form.print())))
+  }
+  fn match_pattern(&self, pattern: &Literal, input: &Literal, bindings: &mut Bindings) -> bool {
+    match pattern {
+      Literal::Atom(_, _) | Literal::Syntax(_, _, _) => {
+        let name = symbol_name(pattern).unwrap();
+        if name == "_" {
+          true
+        }
+        else if self.literals.iter().any(|l| l == name) {
+          symbol_name(input) == Some(name)
+        }
+        else {
+          bindings.insert(name.to_string(), Binding::One(input.clone()));
+          true
+        }
+      }
+      Literal::List(p, _) => {
+        match input {
+          Literal::List(i, _) => {
+            self.match_list(p, i, bindings)
+          }
+          Literal::Nil => {
+            self.match_list(p, &[], bindings)
+          }
+          _ => {
+            false
+          }
+        }
+      }
+      _ => {
+        pattern.is_equal(input)
+      }
+    }
+  }
+  //Matches a list pattern which may contain one ellipsis, like (a b ... c)
+  fn match_list(&self, pattern: &[Literal], input: &[Literal], bindings: &mut Bindings) -> bool {
+    let ellipsis = pattern.iter().position(is_ellipsis);
+    match ellipsis {
+      None => {
+        pattern.len() == input.len() && pattern.iter().zip(input.iter()).all(|(p, i)| self.match_pattern(p, i, bindings))
+      }
+      Some(0) => {
+        false
+      }
+      Some(e) => {
+        let repeated = &pattern[e - 1];
+        let before = &pattern[..e - 1];
+        let after = &pattern[e + 1..];
+        if input.len() < before.len() + after.len() {
+          return false;
+        }
+        let middle_end = input.len() - after.len();
+        if !self.match_list(before, &input[..before.len()], bindings) || !self.match_list(after, &input[middle_end..], bindings) {
+          return false;
+        }
+        let mut sequences: HashMap<String, Vec<Binding>> = self.pattern_vars(repeated).into_iter().map(|v| (v, Vec::new())).collect();
+        for item in &input[before.len()..middle_end] {
+          let mut inner = HashMap::new();
+          if !self.match_pattern(repeated, item, &mut inner) {
+            return false;
+          }
+          for (name, b) in inner {
+            sequences.entry(name).or_default().push(b);
+          }
+        }
+        for (name, seq) in sequences {
+          bindings.insert(name, Binding::Many(seq));
+        }
+        true
+      }
+    }
+  }
+  //The variables a pattern binds
+  fn pattern_vars(&self, pattern: &Literal) -> Vec<String> {
+    match pattern {
+      Literal::List(p, _) => {
+        p.iter().flat_map(|i| self.pattern_vars(i)).collect()
+      }
+      _ => {
+        match symbol_name(pattern) {
+          Some(name) if name != "_" && name != ELLIPSIS && !self.literals.iter().any(|l| l == name) => {
+            vec![name.to_string()]
+          }
+          _ => {
+            Vec::new()
+          }
+        }
+      }
+    }
+  }
+}
+
+//Builds the template, marking every identifier that isn't a pattern variable
+fn instantiate(template: &Literal, bindings: &Bindings, mark: usize, scope: &Scope) -> Result<Literal, LispError> {
+  match template {
+    Literal::Atom(_, _) | Literal::Syntax(_, _, _) => {
+      let name = symbol_name(template).unwrap();
+      match bindings.get(name) {
+        Some(Binding::One(v)) => {
+          Ok(v.clone())
+        }
+        Some(Binding::Many(_)) => {
+          Err(syntax_error(format!("pattern variable {} must be followed by ...", name)))
+        }
+        None => {
+          let (mut marks, scope) = match template {
+            Literal::Syntax(_, m, s) => {
+              (m.clone(), s.clone())
+            }
+            _ => {
+              (Vec::new(), scope.clone())
+            }
+          };
+          marks.push(mark);
+          Ok(Literal::Syntax(name.to_string(), marks, scope))
+        }
+      }
+    }
+    Literal::List(items, span) => {
+      let mut out = Vec::new();
+      let mut i = 0;
+      while i < items.len() {
+        if i + 1 < items.len() && is_ellipsis(&items[i + 1]) {
+          out.extend(instantiate_repeated(&items[i], bindings, mark, scope)?);
+          i += 2;
+        }
+        else {
+          out.push(instantiate(&items[i], bindings, mark, scope)?);
+          i += 1;
+        }
+      }
+      Ok(Literal::List(out, *span))
+    }
+    Literal::Vector(_, _) | Literal::Map(_, _) | Literal::Set(_, _) => {
+      //The forms of a collection are instantiated as a list would be, ellipses included
+      match instantiate(&Literal::List(template.collection_forms().unwrap(), None), bindings, mark, scope)? {
+        Literal::List(items, _) => {
+          Ok(template.with_forms(items))
+        }
+        other => {
+          Ok(other)
+        }
+      }
+    }
+    _ => {
+      Ok(template.clone())
+    }
+  }
+}
+
+//Instantiates a template followed by an ellipsis once per matched element
+fn instantiate_repeated(template: &Literal, bindings: &Bindings, mark: usize, scope: &Scope) -> Result<Vec<Literal>, LispError> {
+  let vars: Vec<(&String, &Vec<Binding>)> = template_symbols(template).iter().filter_map(|name| {
+    match bindings.get_key_value(name.as_str()) {
+      Some((k, Binding::Many(seq))) => {
+        Some((k, seq))
+      }
+      _ => {
+        None
+      }
+    }
+  }).collect();
+  if vars.is_empty() {
+    return Err(syntax_error(format!("... follows a template without ellipsis variables")))
+  }
+  let count = vars[0].1.len();
+  if vars.iter().any(|(_, seq)| seq.len() != count) {
+    return Err(syntax_error(format!("ellipsis variables matched sequences of different lengths")))
+  }
+  let mut out = Vec::new();
+  for n in 0..count {
+    let mut inner = bindings.clone();
+    for (name, seq) in vars.iter() {
+      inner.insert(name.to_string(), seq[n].clone());
+    }
+    out.push(instantiate(template, &inner, mark, scope)?);
+  }
+  Ok(out)
+}
+
+fn template_symbols(template: &Literal) -> Vec<String> {
+  match template {
+    Literal::List(items, _) => {
+      items.iter().flat_map(template_symbols).collect()
+    }
+    Literal::Vector(_, _) | Literal::Map(_, _) | Literal::Set(_, _) => {
+      template.collection_forms().unwrap().iter().flat_map(template_symbols).collect()
+    }
+    _ => {
+      symbol_name(template).map(|s| vec![s.to_string()]).unwrap_or_default()
+    }
+  }
+}
+
+//Gives the marked identifiers bound by binding forms inside an expansion
+//fresh names, and turns those naming syntax back into plain atoms
+struct Renamer {
+  scopes: Vec<HashMap<(String, Vec<usize>), String>>,
+}
+
+impl Renamer {
+  fn lookup(&self, name: &str, marks: &[usize], scope: &Scope) -> Literal {
+    let key = (name.to_string(), marks.to_vec());
+    for bound in self.scopes.iter().rev() {
+      if let Some(renamed) = bound.get(&key) {
+        return Literal::Atom(renamed.clone(), None);
+      }
+    }
+    if is_syntax(name, scope) {
+      Literal::Atom(name.to_string(), None)
+    }
+    else {
+      Literal::Syntax(name.to_string(), marks.to_vec(), scope.clone())
+    }
+  }
+  //Introduces a binder into the innermost scope
+  fn bind(&mut self, binder: &Literal) -> Literal {
+    match binder {
+      //Lambda list keywords like &rest and the dot keep their meaning
+      Literal::Syntax(name, marks, _) if !name.starts_with('&') && name != "." => {
+        let renamed = format!("{}#{}", name, fresh());
+        self.scopes.last_mut().unwrap().insert((name.clone(), marks.clone()), renamed.clone());
+        Literal::Atom(renamed, None)
+      }
+      Literal::List(l, span) if !l.is_empty() => {
+        //Parameters with default values, like (port 80)
+        let mut items = vec![self.bind(&l[0])];
+        items.extend(l[1..].iter().map(|i| self.resolve(i)));
+        Literal::List(items, *span)
+      }
+      _ => {
+        strip(binder)
+      }
+    }
+  }
+  fn bind_all(&mut self, binders: &Literal) -> Literal {
+    match binders {
+      Literal::List(l, span) => {
+        Literal::List(l.iter().map(|b| self.bind(b)).collect(), *span)
+      }
+      _ => {
+        self.bind(binders)
+      }
+    }
+  }
+  //Resolves forms in a new scope holding the given binders
+  fn scoped(&mut self, head: &[Literal], binders: &Literal, body: &[Literal], span: &Option<Span>) -> Literal {
+    self.scopes.push(HashMap::new());
+    let mut items: Vec<Literal> = head.iter().map(strip).collect();
+    items.push(self.bind_all(binders));
+    items.extend(body.iter().map(|b| self.resolve(b)));
+    self.scopes.pop();
+    Literal::List(items, *span)
+  }
+  fn resolve(&mut self, form: &Literal) -> Literal {
+    match form {
+      Literal::Syntax(name, marks, scope) => {
+        self.lookup(name, marks, scope)
+      }
+      Literal::List(items, span) if !items.is_empty() => {
+        let head = symbol_name(&items[0]).unwrap_or("");
+        match (head, items.len()) {
+          ("quote", _) | ("define-syntax", _) => {
+            strip(form)
+          }
+          ("quasiquote", 2) => {
+            Literal::List(vec![strip(&items[0]), self.resolve_template(&items[1])], *span)
+          }
+          ("lambda", 3..) | ("fn", 3..) => {
+            self.scoped(&items[..1], &items[1], &items[2..], span)
+          }
+          ("defn", 4..) | ("defmacro", 4..) => {
+            self.scoped(&items[..2], &items[2], &items[3..], span)
+          }
+          ("define", 3..) => {
+            match &items[1] {
+              Literal::List(sig, sig_span) if !sig.is_empty() => {
+                self.scopes.push(HashMap::new());
+                let mut new_sig = vec![strip(&sig[0])];
+                new_sig.extend(sig[1..].iter().map(|p| self.bind(p)));
+                let mut out = vec![strip(&items[0]), Literal::List(new_sig, *sig_span)];
+                out.extend(items[2..].iter().map(|b| self.resolve(b)));
+                self.scopes.pop();
+                Literal::List(out, *span)
+              }
+              _ => {
+                let mut out = vec![strip(&items[0]), strip(&items[1])];
+                out.extend(items[2..].iter().map(|b| self.resolve(b)));
+                Literal::List(out, *span)
+              }
+            }
+          }
+          ("let", 3..) | ("let*", 3..) | ("letrec", 3..) => {
+            self.resolve_let(head, items, span)
+          }
+          //Catch clauses bind their variable, and name kinds rather than variables
+          ("try", _) => {
+            Literal::List(items.iter().map(|i| {
+              match i {
+                Literal::List(l, s) if l.len() >= 3 && symbol_name(&l[0]) == Some("catch") => {
+                  let binders = if let Literal::List(_, _) = l[1] { 1 } else { 2 };
+                  self.scoped(&l[..binders], &l[binders], &l[binders + 1..], s)
+                }
+                _ => {
+                  self.resolve(i)
+                }
+              }
+            }).collect(), *span)
+          }
+          ("handler-bind", 2..) => {
+            let bindings = match &items[1] {
+              Literal::List(b, s) => {
+                Literal::List(b.iter().map(|pair| {
+                  match pair {
+                    Literal::List(p, ps) if p.len() == 2 => {
+                      Literal::List(vec![strip(&p[0]), self.resolve(&p[1])], *ps)
+                    }
+                    _ => {
+                      self.resolve(pair)
+                    }
+                  }
+                }).collect(), *s)
+              }
+              other => {
+                self.resolve(other)
+              }
+            };
+            let mut out = vec![strip(&items[0]), bindings];
+            out.extend(items[2..].iter().map(|b| self.resolve(b)));
+            Literal::List(out, *span)
+          }
+          //Each restart is named and takes parameters like a lambda
+          ("restart-case", 2..) => {
+            let mut out = vec![strip(&items[0]), self.resolve(&items[1])];
+            out.extend(items[2..].iter().map(|clause| {
+              match clause {
+                Literal::List(l, s) if l.len() >= 2 => {
+                  self.scoped(&l[..1], &l[1], &l[2..], s)
+                }
+                _ => {
+                  self.resolve(clause)
+                }
+              }
+            }));
+            Literal::List(out, *span)
+          }
+          _ => {
+            Literal::List(items.iter().map(|i| self.resolve(i)).collect(), *span)
+          }
+        }
+      }
+      Literal::Vector(_, _) | Literal::Map(_, _) | Literal::Set(_, _) => {
+        form.with_forms(form.collection_forms().unwrap().iter().map(|i| self.resolve(i)).collect())
+      }
+      _ => {
+        form.clone()
+      }
+    }
+  }
+  fn resolve_let(&mut self, head: &str, items: &[Literal], span: &Option<Span>) -> Literal {
+    let bindings = match &items[1] {
+      Literal::List(b, _) => {
+        b
+      }
+      _ => {
+        return Literal::List(items.iter().map(|i| self.resolve(i)).collect(), *span)
+      }
+    };
+    //let initializers are outside the new scope, let* and letrec ones inside
+    let inits: Vec<Option<Literal>> = if head == "let" {
+      bindings.iter().map(|b| self.resolve_init(b)).collect()
+    }
+    else {
+      Vec::new()
+    };
+    self.scopes.push(HashMap::new());
+    let names: Vec<Literal> = bindings.iter().map(|b| {
+      match b {
+        Literal::List(pair, _) if !pair.is_empty() => {
+          self.bind(&pair[0])
+        }
+        _ => {
+          self.bind(b)
+        }
+      }
+    }).collect();
+    let inits = if head == "let" {
+      inits
+    }
+    else {
+      bindings.iter().map(|b| self.resolve_init(b)).collect()
+    };
+    let new_bindings = names.into_iter().zip(inits).zip(bindings.iter()).map(|((name, init), b)| {
+      match (init, b) {
+        (Some(init), Literal::List(_, s)) => {
+          Literal::List(vec![name, init], *s)
+        }
+        _ => {
+          name
+        }
+      }
+    }).collect();
+    let mut out = vec![strip(&items[0]), Literal::List(new_bindings, None)];
+    out.extend(items[2..].iter().map(|b| self.resolve(b)));
+    self.scopes.pop();
+    Literal::List(out, *span)
+  }
+  fn resolve_init(&mut self, binding: &Literal) -> Option<Literal> {
+    match binding {
+      Literal::List(pair, _) if pair.len() == 2 => {
+        Some(self.resolve(&pair[1]))
+      }
+      _ => {
+        None
+      }
+    }
+  }
+  //Quasiquote templates are data except for their unquoted parts
+  fn resolve_template(&mut self, template: &Literal) -> Literal {
+    match template {
+      Literal::List(items, span) => {
+        match symbol_name(items.first().unwrap_or(&Literal::Nil)) {
+          Some("unquote") | Some("unquote-splicing") if items.len() == 2 => {
+            Literal::List(vec![strip(&items[0]), self.resolve(&items[1])], *span)
+          }
+          _ => {
+            Literal::List(items.iter().map(|i| self.resolve_template(i)).collect(), *span)
+          }
+        }
+      }
+      _ => {
+        strip(template)
+      }
+    }
+  }
+}
+
+//Whether a name is one forms recognize, like else, or names a special form or macro where the macro was defined
+fn is_syntax(name: &str, scope: &Scope) -> bool {
+  if name.starts_with('&') || AUXILIARY.contains(&name) {
+    return true;
+  }
+  let value = scope.0.borrow().lookup_value(name);
+  match value {
+    Ok(Literal::Func(exec)) => {
+      matches!(exec.as_ref(), Executable::Builtin(_) | Executable::SpecialForm(_) | Executable::Macro(_, _, _) | Executable::SyntaxRules(_))
+    }
+    _ => {
+      false
+    }
+  }
+}
+
+//Removes all marks, used for quoted data and names that are never renamed
+fn strip(form: &Literal) -> Literal {
+  match form {
+    Literal::Syntax(name, _, _) => {
+      Literal::Atom(name.clone(), None)
+    }
+    Literal::List(items, span) => {
+      Literal::List(items.iter().map(strip).collect(), *span)
+    }
+    Literal::Vector(_, _) | Literal::Map(_, _) | Literal::Set(_, _) => {
+      form.with_forms(form.collection_forms().unwrap().iter().map(strip).collect())
+    }
+    _ => {
+      form.clone()
+    }
+  }
+}
+
+//Expands a single use of a syntax-rules macro into plain code
+pub fn expand_use(rules: &SyntaxRules, form: &Literal) -> Result<Literal, LispError> {
+  let transcribed = rules.transcribe(form)?;
+  let mut renamer = Renamer { scopes: vec![HashMap::new()] };
+  Ok(renamer.resolve(&transcribed))
+}
+
+//Returns the syntax-rules macro a form invokes, if its head names one
+pub fn syntax_rules_for(env: &Rc<RefCell<ExecutionEnv>>, form: &Literal) -> Option<Rc<Executable>> {
+  if let Literal::List(l, _) = form {
+    if let Some(Literal::Atom(name, _)) = l.first() {
+      if let Ok(Literal::Func(exec)) = env.borrow().lookup_value(name) {
+        if let Executable::SyntaxRules(_) = exec.as_ref() {
+          return Some(exec);
+        }
+      }
+    }
+  }
+  None
+}
+
+//The expander pass run on each top level form before it is evaluated,
+//rewriting every use of a syntax-rules macro already defined in env.
+//Macros defined inside the form itself are expanded when evaluated instead.
+pub fn expand(env: &Rc<RefCell<ExecutionEnv>>, form: Literal) -> Result<Literal, LispError> {
+  if let Some(exec) = syntax_rules_for(env, &form) {
+    if let Executable::SyntaxRules(rules) = exec.as_ref() {
+      let expanded = expand_use(rules, &form)?;
+      return expand(env, expanded);
+    }
+  }
+  match form {
+    Literal::List(items, span) => {
+      match items.first().and_then(symbol_name) {
+        Some("quote") | Some("quasiquote") | Some("define-syntax") => {
+          return Ok(Literal::List(items, span))
+        }
+        _ => {}
+      }
+      let expanded: Result<Vec<Literal>, LispError> = items.into_iter().map(|i| expand(env, i)).collect();
+      Ok(Literal::List(expanded?, span))
+    }
+    Literal::Vector(_, _) | Literal::Map(_, _) | Literal::Set(_, _) => {
+      let expanded: Result<Vec<Literal>, LispError> = form.collection_forms().unwrap().into_iter().map(|i| expand(env, i)).collect();
+      Ok(form.with_forms(expanded?))
+    }
+    _ => {
+      Ok(form)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::env::tests::assert_value;
+
+  const INC: &str = "(define-syntax inc! (syntax-rules () ((_ x) (set! x (+ x 1)))))
+    (define y 0)";
+
+  #[test]
+  fn template_globals_ignore_use_site_bindings() {
+    assert_value(&format!("{} (let ((+ -)) (inc! y)) y", INC), "1");
+    assert_value(&format!("{} (defn bump () (let ((+ -)) (inc! y))) (set! bump (compile bump)) (bump) (bump) y", INC), "2");
+  }
+
+  //The helper the template calls isn't defined until after the macro is used
+  #[test]
+  fn parameters_never_capture_template_identifiers() {
+    let source = "(define-syntax call-helper (syntax-rules () ((_ x) (helper x))))
+      (defn use (helper) (call-helper 1))
+      (defn helper (x) (* x 100))";
+    assert_value(&format!("{} (use (lambda (x) 'captured))", source), "100");
+    assert_value(&format!("{} (set! use (compile use)) (use (lambda (x) 'captured))", source), "100");
+  }
+
+  #[test]
+  fn local_macros_refer_to_their_own_scope() {
+    assert_value("(defn make ()
+        (let ((secret 1))
+          (define-syntax peek (syntax-rules () ((_) secret)))
+          (let ((secret 2)) (list secret (peek)))))
+      (make)", "(2 1)");
+  }
+
+  #[test]
+  fn template_names_that_are_not_variables_stay_syntax() {
+    assert_value("(define-syntax safe (syntax-rules () ((_ body) (try body (catch error (e) (error-message e))))))
+      (safe (error \"boom\"))", "\"boom\"");
+    assert_value("(define-syntax skipping (syntax-rules () ((_ body)
+        (handler-bind ((error (lambda (c) (invoke-restart 'skip 5)))) (restart-case body (skip (v) v))))))
+      (skipping (error \"x\"))", "5");
+    assert_value("(define-syntax sign (syntax-rules () ((_ n) (cond ((< n 0) -1) (else 1)))))
+      (let ((else false)) (sign 5))", "1");
+  }
+}
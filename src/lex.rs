@@ -1,4 +1,76 @@
 use crate::lex::Bracket::*;
+use crate::num::Number;
+use std::fmt;
+
+//A location in the source text, lines and columns start from 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Position {
+  pub line: usize,
+  pub column: usize,
+  pub offset: usize,
+}
+
+impl Position {
+  pub fn start() -> Self {
+    Position { line: 1, column: 1, offset: 0 }
+  }
+  //Moves the position past the given character
+  fn advance(&mut self, c: char) {
+    self.offset += c.len_utf8();
+    if c == '\n' {
+      self.line += 1;
+      self.column = 1;
+    }
+    else {
+      self.column += 1;
+    }
+  }
+}
+
+impl fmt::Display for Position {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "line {}, column {}", self.line, self.column)
+  }
+}
+
+//The region of source text a token or form was read from,
+//end is the position just past its last character
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+  pub start: Position,
+  pub end: Position,
+}
+
+impl Span {
+  pub fn new(start: Position, end: Position) -> Self {
+    Span { start, end }
+  }
+}
+
+impl fmt::Display for Span {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.start)
+  }
+}
+
+//Error produced when source text can't be lexed or parsed
+#[derive(Clone, Debug)]
+pub struct SyntaxError {
+  pub message: String,
+  pub position: Position,
+}
+
+impl SyntaxError {
+  pub fn new(message: String, position: Position) -> Self {
+    SyntaxError { message, position }
+  }
+}
+
+impl fmt::Display for SyntaxError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Syntax Error at {}: {}", self.position, self.message)
+  }
+}
 
 #[derive(Debug)]
 enum LexerMode {
@@ -8,6 +80,10 @@ enum LexerMode {
   Ident,
   Key,
   SpecialCharacters,
+  //Skipping to the end of the line after a ;
+  LineComment,
+  //Inside #| |# comments nested this deep, with the previous character
+  BlockComment(usize, char),
 }
 
 #[derive(Debug)]
@@ -15,9 +91,16 @@ pub struct Lexer {
   stream: String,
   mode: LexerMode,
   pub out: Vec<Token>,
+  //Source span of each token in out
+  pub spans: Vec<Span>,
+  //Position of the character being lexed and of the token being built
+  pos: Position,
+  start: Position,
   str_buffer: Vec<char>,
   num_buffer: Vec<char>,
   ident_buffer: Vec<char>,
+  //Where a backslash escape in a string started and what follows it so far
+  escape: Option<(Position, String)>,
 }
 
 impl Lexer {
@@ -35,109 +118,243 @@ impl Lexer {
       stream: String::new(),
       mode: LexerMode::Neutral,
       out: Vec::new(),
+      spans: Vec::new(),
+      pos: Position::start(),
+      start: Position::start(),
       str_buffer: Vec::new(),
       num_buffer: Vec::new(),
       ident_buffer: Vec::new(),
+      escape: None,
     }
   }
   pub fn _dump(&mut self) -> Vec<String> {
     let mut ret = Vec::new();
-    for (_, v) in self.out.clone().into_iter().enumerate() {
+    for v in self.out.clone().into_iter() {
       ret.push(v._format())
     }
     ret
   }
-  pub fn tokenize(&mut self) {
-    for (i,c) in self.get_stream().chars().enumerate() {
+  //Lexes a whole script, where input that ends inside a string or comment is an error
+  pub fn tokenize(&mut self) -> Result<(), SyntaxError> {
+    self.lex(true)
+  }
+  //Lexes input that may continue on a later line, as the REPL reads it,
+  //leaving is_complete to tell whether it does
+  pub fn tokenize_partial(&mut self) -> Result<(), SyntaxError> {
+    self.lex(false)
+  }
+  fn lex(&mut self, complete: bool) -> Result<(), SyntaxError> {
+    for c in self.get_stream().chars() {
+      if let LexerMode::Neutral = self.mode {
+        self.start = self.pos;
+      }
+      if let LexerMode::String = self.mode {
+        //Inside a string every character is taken as written
+        self.match_string_char(c)?;
+        self.pos.advance(c);
+        continue;
+      }
+      if let LexerMode::LineComment = self.mode {
+        if c == '\n' {
+          self.mode = LexerMode::Neutral;
+        }
+        self.pos.advance(c);
+        continue;
+      }
+      if let LexerMode::BlockComment(depth, prev) = self.mode {
+        self.mode = match (prev, c) {
+          ('|', '#') if depth == 1 => {
+            LexerMode::Neutral
+          }
+          ('|', '#') => {
+            LexerMode::BlockComment(depth - 1, ' ')
+          }
+          ('#', '|') => {
+            LexerMode::BlockComment(depth + 1, ' ')
+          }
+          _ => {
+            LexerMode::BlockComment(depth, c)
+          }
+        };
+        self.pos.advance(c);
+        continue;
+      }
       match c {
         '\"' => {
-          if let LexerMode::String = self.mode {
-            self.flush();
-            self.mode = LexerMode::Neutral;
-          }
-          else {
-          self.flush();
-          self.mode = LexerMode::String;            
-          } 
+          self.flush()?;
+          self.mode = LexerMode::String;
         },
         'a'..='z' | 'A'..='Z' => {
-          self.match_char(c);
+          self.match_char(c)?;
         },
         '0'..='9' => {
-          self.match_digit(c);
+          self.match_digit(c)?;
         }
-        ' ' | '\t' | '\n' => {
+        c if c.is_whitespace() => {
           //Whitespace flushes all buffers and returns to neutral
-          //Conversly, whitespace in strings is ignored
-          if let LexerMode::String = self.mode {
-            self.match_char(c);
-          }
-          else {
-            self.flush();
-            self.mode = LexerMode::Neutral;
-          }
+          self.flush()?;
+          self.mode = LexerMode::Neutral;
+        }
+        '{' if self.follows_hash() => {
+          //#{ opens a set
+          self.num_buffer.clear();
+          let mut end = self.pos;
+          end.advance(c);
+          self.push_token(Token::Bracket(HashCurlyOpen), Span::new(self.start, end));
+          self.mode = LexerMode::Neutral;
+        }
+        '(' | ')' | '{' | '}' | '[' | ']' => {
+          self.match_bracket(c)?;
+        }
+        '\'' | '`' | ',' => {
+          self.match_quote(c)?;
+        }
+        '@' if self.follows_unquote() => {
+          //,@ is a single token
+          let span = self.spans.pop().unwrap();
+          self.out.pop();
+          let mut end = self.pos;
+          end.advance(c);
+          self.push_token(Token::Quote(format!("unquote-splicing")), Span::new(span.start, end));
+        }
+        '@' |'!' | '_' | '$'..='\'' | '*'..='/' | '<'..='?' | '^' => {
+          self.match_special_char(c)?;
+        }
+        '#' => {
+          self.match_hash(c)?;
+        }
+        ';' if self.follows_hash() => {
+          //#; comments out the form after it, which the parser skips
+          self.num_buffer.clear();
+          let mut end = self.pos;
+          end.advance(c);
+          self.push_token(Token::DatumComment, Span::new(self.start, end));
+          self.mode = LexerMode::Neutral;
         }
-        '(' | ')' | '{' | '}' | '[' | ']' | '<' | '>' => {
-          self.match_bracket(c);
+        ';' => {
+          self.flush()?;
+          self.mode = LexerMode::LineComment;
         }
-        '@' |'!' | '$'..='\'' | '*'..='/' | ';'..='?' | '^' => {
-          self.match_special_char(c);
+        '|' if self.follows_hash() => {
+          self.num_buffer.clear();
+          self.mode = LexerMode::BlockComment(1, ' ');
         }
         ':' => {
-          self.flush();
+          self.flush()?;
           self.mode = LexerMode::Key;
           self.ident_buffer.push(':')
         }
+        c if !c.is_ascii() => {
+          //Letters and symbols from any other script can be used in names
+          self.match_char(c)?;
+        }
         _ => {
-          panic!("Unexpected token ({:?})encountered at char: {}", c, i)
+          return Err(SyntaxError::new(format!("Unexpected character {:?}", c), self.pos));
+        }
+      }
+      self.pos.advance(c);
+    }
+    if complete {
+      //The start of the token is where the string's quote or the comment's #| was
+      match (&self.mode, &self.escape) {
+        (LexerMode::BlockComment(_, _), _) => {
+          return Err(SyntaxError::new(format!("Unclosed block comment"), self.start));
+        }
+        (LexerMode::String, Some((start, code))) => {
+          return Err(SyntaxError::new(format!("Malformed escape \\{}", code), *start));
         }
+        (LexerMode::String, None) => {
+          return Err(SyntaxError::new(format!("Unterminated string"), self.start));
+        }
+        _ => {}
       }
     }
-    self.flush();
-    self.out.push(Token::EndOfFile);
+    self.flush()?;
+    self.push_token(Token::EndOfFile, Span::new(self.pos, self.pos));
+    Ok(())
+  }
+  fn push_token(&mut self, tok: Token, span: Span) {
+    self.out.push(tok);
+    self.spans.push(span);
   }
   //Flushes all lexer buffers
   //(num_buffer, ident_buffer, and str_buffer)
   //This clears all buffers and pushes their contents as Tokens
-  fn flush(&mut self) {
+  fn flush(&mut self) -> Result<(), SyntaxError> {
+    self.flush_until(self.pos)
+  }
+  //Flushes the buffers, ending the pushed token at the given position
+  fn flush_until(&mut self, end: Position) -> Result<(), SyntaxError> {
+    let start = self.start;
+    let span = Span::new(start, end);
+    self.start = self.pos;
     match self.mode {
-      LexerMode::Neutral => {
-        return;
+      LexerMode::Neutral | LexerMode::LineComment | LexerMode::BlockComment(_, _) => {
+        return Ok(());
       }
       LexerMode::String => {
         let s = self.str_buffer.clone().into_iter().collect::<String>();
         self.str_buffer.clear();
-        self.out.push(Token::String(s));
+        self.push_token(Token::String(s), span);
       },
       LexerMode::Numeric => {
-        let i = self.num_buffer.clone().into_iter().collect::<String>().parse::<f64>().unwrap();
+        let s = self.num_buffer.clone().into_iter().collect::<String>();
         self.num_buffer.clear();
-        self.out.push(Token::Number(i));
+        match Number::parse(&s) {
+          Some(n) => {
+            self.push_token(Token::Number(n), span);
+          }
+          None => {
+            return Err(SyntaxError::new(format!("Malformed number {:?}", s), start));
+          }
+        }
       },
       LexerMode::Ident => {
         let s = self.ident_buffer.clone().into_iter().collect::<String>();
         self.ident_buffer.clear();
-        self.out.push(Token::Ident(s));
+        self.push_token(Token::Ident(s), span);
       }
       LexerMode::Key => {
         let s = self.ident_buffer.clone().into_iter().collect::<String>();
         self.ident_buffer.clear();
-        self.out.push(Token::Key(s));
+        self.push_token(Token::Key(s), span);
       }
       LexerMode::SpecialCharacters => {
         let s = self.ident_buffer.clone().into_iter().collect::<String>();
         self.ident_buffer.clear();
-        self.out.push(Token::Ident(s));
+        self.push_token(Token::Ident(s), span);
+      }
+    }
+    Ok(())
+  }
+  //Checks whether the tokenized input forms a complete expression,
+  //i.e. no string literal or block comment is left open and every opened bracket is closed
+  pub fn is_complete(&self) -> bool {
+    if let LexerMode::String | LexerMode::BlockComment(_, _) = self.mode {
+      return false;
+    }
+    let mut depth = 0;
+    for t in self.out.iter() {
+      match t {
+        Token::Bracket(ParenOpen | BracketOpen | CurlyOpen | HashCurlyOpen) => {
+          depth += 1;
+        }
+        Token::Bracket(ParenClose | BracketClose | CurlyClose) => {
+          depth -= 1;
+        }
+        _ => {}
       }
     }
+    depth <= 0
   }
   //Returns the current stream as a string
+  #[allow(clippy::needless_return)]
   fn get_stream(&mut self) -> String {
     return self.stream.clone();
   }
   //This function decides what to do when a character is encountered
   //Result is based on the Lexer mode
-  fn match_char(&mut self, c: char) {
+  fn match_char(&mut self, c: char) -> Result<(), SyntaxError> {
     match self.mode {
       LexerMode::Neutral => {
         self.mode = LexerMode::Ident;
@@ -146,10 +363,12 @@ impl Lexer {
       LexerMode::String => {
         self.str_buffer.push(c);
       }
+      LexerMode::LineComment | LexerMode::BlockComment(_, _) => {
+        //Comments are skipped before characters get here
+      }
       LexerMode::Numeric => {
-        self.flush();
-        self.mode = LexerMode::Ident;
-        self.ident_buffer.push(c);
+        //Exponents and hex digits, anything else makes a malformed number
+        self.num_buffer.push(c);
       }
       LexerMode::Ident => {
         self.ident_buffer.push(c);
@@ -158,14 +377,15 @@ impl Lexer {
         self.ident_buffer.push(c);
       }
       LexerMode::SpecialCharacters => {
-        self.flush();
-        self.mode = LexerMode::SpecialCharacters;
+        //Names like &rest and ->x start with special characters
+        self.mode = LexerMode::Ident;
         self.ident_buffer.push(c);
       }
     }
+    Ok(())
   }
   //Just like match_char() -> (), but used for digits
-  fn match_digit(&mut self, d: char) {
+  fn match_digit(&mut self, d: char) -> Result<(), SyntaxError> {
     match self.mode {
       LexerMode::Neutral => {
         self.mode = LexerMode::Numeric;
@@ -174,6 +394,9 @@ impl Lexer {
       LexerMode::String => {
         self.str_buffer.push(d);
       }
+      LexerMode::LineComment | LexerMode::BlockComment(_, _) => {
+        //Comments are skipped before characters get here
+      }
       LexerMode::Numeric => {
         self.num_buffer.push(d);
       }
@@ -184,13 +407,23 @@ impl Lexer {
         self.ident_buffer.push(d);
       }
       LexerMode::SpecialCharacters => {
-        self.flush();
-        self.mode = LexerMode::SpecialCharacters;
-        self.num_buffer.push(d);
+        //A sign or point before a digit starts a number, as in -5 or .5
+        let prefix = self.ident_buffer.iter().collect::<String>();
+        if matches!(prefix.as_str(), "+" | "-" | "." | "+." | "-.") {
+          self.ident_buffer.clear();
+          self.num_buffer.extend(prefix.chars());
+          self.mode = LexerMode::Numeric;
+          self.num_buffer.push(d);
+        }
+        else {
+          self.mode = LexerMode::Ident;
+          self.ident_buffer.push(d);
+        }
       }
     }
+    Ok(())
   }
-  fn match_special_char(&mut self, c: char) {
+  fn match_special_char(&mut self, c: char) -> Result<(), SyntaxError> {
     match self.mode {
       LexerMode::SpecialCharacters => {
         self.ident_buffer.push(c);
@@ -202,59 +435,195 @@ impl Lexer {
       LexerMode::String => {
         self.str_buffer.push(c);
       }
+      LexerMode::LineComment | LexerMode::BlockComment(_, _) => {
+        //Comments are skipped before characters get here
+      }
       LexerMode::Numeric => {
-        self.flush();
-        self.mode = LexerMode::SpecialCharacters;
-        self.ident_buffer.push(c);
+        //Points, exponent signs, fractions and digit separators
+        self.num_buffer.push(c);
       }
-      LexerMode::Ident => {
+      LexerMode::Ident | LexerMode::Key => {
+        //Names like max-retries and empty? keep their special characters
         self.ident_buffer.push(c);
       }
-      LexerMode::Key => {
-        self.flush();
-        self.mode = LexerMode::SpecialCharacters;
-        self.ident_buffer.push(c);
+    }
+    Ok(())
+  }
+  //Handles a character inside a string literal, ending it at an
+  //unescaped quote and decoding escapes like \n and \u{3bb}
+  fn match_string_char(&mut self, c: char) -> Result<(), SyntaxError> {
+    let (start, mut escape) = match self.escape.take() {
+      Some(escape) => {
+        escape
+      }
+      None => {
+        match c {
+          '"' => {
+            //The closing quote belongs to the string token
+            let mut end = self.pos;
+            end.advance(c);
+            self.flush_until(end)?;
+            self.mode = LexerMode::Neutral;
+          }
+          '\\' => {
+            self.escape = Some((self.pos, String::new()));
+          }
+          _ => {
+            self.str_buffer.push(c);
+          }
+        }
+        return Ok(());
+      }
+    };
+    escape.push(c);
+    let decoded = match escape.as_str() {
+      "n" => {
+        '\n'
+      }
+      "t" => {
+        '\t'
+      }
+      "r" => {
+        '\r'
+      }
+      "0" => {
+        '\0'
+      }
+      "\\" => {
+        '\\'
+      }
+      "\"" => {
+        '"'
+      }
+      "u" | "u{" => {
+        self.escape = Some((start, escape));
+        return Ok(());
+      }
+      code if code.starts_with("u{") && c != '}' => {
+        if !c.is_ascii_hexdigit() || code.len() > 8 {
+          return Err(SyntaxError::new(format!("Malformed escape \\{}", code), start));
+        }
+        self.escape = Some((start, escape));
+        return Ok(());
+      }
+      code if code.starts_with("u{") => {
+        let digits = &code[2..code.len() - 1];
+        match u32::from_str_radix(digits, 16).ok().and_then(char::from_u32) {
+          Some(decoded) => {
+            decoded
+          }
+          None => {
+            return Err(SyntaxError::new(format!("Malformed escape \\{}", code), start));
+          }
+        }
+      }
+      code => {
+        return Err(SyntaxError::new(format!("Unknown escape \\{}", code), start));
+      }
+    };
+    self.str_buffer.push(decoded);
+    Ok(())
+  }
+  //# starts a radix prefix like #x1F, and is not allowed in names
+  fn match_hash(&mut self, h: char) -> Result<(), SyntaxError> {
+    match self.mode {
+      LexerMode::String => {
+        self.str_buffer.push(h);
+      }
+      LexerMode::Neutral => {
+        self.mode = LexerMode::Numeric;
+        self.num_buffer.push(h);
+      }
+      LexerMode::Numeric => {
+        self.num_buffer.push(h);
+      }
+      _ => {
+        return Err(SyntaxError::new(format!("Unexpected character {:?}", h), self.pos));
       }
     }
+    Ok(())
   }
   //Just like match_char and match_digit but handles brackets
-  fn match_bracket(&mut self, b: char) {
+  fn match_bracket(&mut self, b: char) -> Result<(), SyntaxError> {
     if let LexerMode::String = self.mode {
       self.str_buffer.push(b);
-      return;
+      return Ok(());
     }
-    self.flush();
+    self.flush()?;
     self.mode = LexerMode::Neutral;
+    let mut end = self.pos;
+    end.advance(b);
+    let span = Span::new(self.pos, end);
     match b {
       '(' => {
-        self.out.push(Token::Bracket(ParenOpen));
+        self.push_token(Token::Bracket(ParenOpen), span);
       }
       ')' => {
-        self.out.push(Token::Bracket(ParenClose));
+        self.push_token(Token::Bracket(ParenClose), span);
       }
       '[' => {
-        self.out.push(Token::Bracket(BracketOpen))
+        self.push_token(Token::Bracket(BracketOpen), span);
       }
       ']' => {
-        self.out.push(Token::Bracket(BracketClose))
+        self.push_token(Token::Bracket(BracketClose), span);
       }
       '{' => {
-        self.out.push(Token::Bracket(CurlyOpen));
+        self.push_token(Token::Bracket(CurlyOpen), span);
       }
       '}' => {
-        self.out.push(Token::Bracket(CurlyClose));
+        self.push_token(Token::Bracket(CurlyClose), span);
+      }
+      _ => {
+        return Err(SyntaxError::new(format!("Catastrophic error occurred during parsing of bracket tokens"), self.pos));
       }
-      '<' => {
-        self.out.push(Token::Bracket(AngleOpen));
+    }
+    self.mode = LexerMode::Neutral;
+    Ok(())
+  }
+  //Handles the reader macro characters ' ` and ,
+  //which become tokens naming the form they expand into
+  fn match_quote(&mut self, q: char) -> Result<(), SyntaxError> {
+    if let LexerMode::String = self.mode {
+      self.str_buffer.push(q);
+      return Ok(());
+    }
+    self.flush()?;
+    self.mode = LexerMode::Neutral;
+    let mut end = self.pos;
+    end.advance(q);
+    let name = match q {
+      '\'' => {
+        "quote"
       }
-      '>' => {
-        self.out.push(Token::Bracket(AngleClose));
+      '`' => {
+        "quasiquote"
       }
       _ => {
-        panic!("Catastrophic error occurred during parsing of bracket tokens")
+        "unquote"
+      }
+    };
+    self.push_token(Token::Quote(name.to_string()), Span::new(self.pos, end));
+    Ok(())
+  }
+  //Checks if the character being lexed directly follows a lone #
+  fn follows_hash(&self) -> bool {
+    matches!(self.mode, LexerMode::Numeric) && self.num_buffer == ['#']
+  }
+  //Checks if the character being lexed directly follows a , token
+  fn follows_unquote(&self) -> bool {
+    if let LexerMode::Neutral = self.mode {
+      match (self.out.last(), self.spans.last()) {
+        (Some(Token::Quote(name)), Some(span)) => {
+          name == "unquote" && span.end == self.pos
+        }
+        _ => {
+          false
+        }
       }
     }
-    self.mode = LexerMode::Neutral;
+    else {
+      false
+    }
   }
   pub fn substitute(&mut self) {
     let mut new_tokens: Vec<Token> = Vec::new();
@@ -287,10 +656,14 @@ impl Lexer {
 pub enum Token {
   Bracket(Bracket),
   String(String),
-  Number(f64),
+  Number(Number),
   Ident(String),
   Key(String),
   Reserved(String),
+  //A reader macro prefix, holding the name of the form it expands into
+  Quote(String),
+  //#; which makes the parser skip the next form
+  DatumComment,
   EndOfFile,
 }
 
@@ -315,6 +688,12 @@ impl Token {
       Token::Reserved(a) => {
         format!("Token Type: Reserved Ident\nToken Val: {:?}", a)
       }
+      Token::Quote(a) => {
+        format!("Token Type: Quote\nToken Val: {:?}", a)
+      }
+      Token::DatumComment => {
+        format!("Token Type: Datum Comment")
+      }
       Token::EndOfFile => {
         format!("Token Type: End Of Stream")
       }
@@ -322,7 +701,8 @@ impl Token {
   }
 }
 
-#[derive(Clone, Debug, Copy)]
+#[derive(Clone, Debug, Copy, PartialEq)]
+#[allow(clippy::enum_variant_names)]
 pub enum Bracket {
   ParenOpen,
   ParenClose,
@@ -330,12 +710,12 @@ pub enum Bracket {
   BracketClose,
   CurlyOpen,
   CurlyClose,
-  AngleOpen,
-  AngleClose,
+  //#{, which is closed by }
+  HashCurlyOpen,
 }
 
 impl Bracket {
-  fn _format(self) -> String {
+  pub fn _format(self) -> String {
     match self {
       Bracket::ParenOpen => {
         "(".to_string()
@@ -355,12 +735,90 @@ impl Bracket {
       Bracket::CurlyClose => {
         "}".to_string()
       }
-      Bracket::AngleOpen => {
-        "<".to_string()
+      Bracket::HashCurlyOpen => {
+        "#{".to_string()
       }
-      Bracket::AngleClose => {
-        ">".to_string()
+    }
+  }
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lex(source: &str) -> Result<Vec<Token>, SyntaxError> {
+    let mut lexer = Lexer::from_string(source.to_string());
+    lexer.tokenize()?;
+    Ok(lexer.out)
+  }
+
+  #[test]
+  fn keywords_keep_special_characters() {
+    let tokens = lex(":max-retries :empty? :set! :*x*").unwrap();
+    let keys: Vec<String> = tokens.into_iter().filter_map(|t| match t {
+      Token::Key(k) => {
+        Some(k)
+      }
+      _ => {
+        None
+      }
+    }).collect();
+    assert_eq!(keys, [":max-retries", ":empty?", ":set!", ":*x*"]);
+  }
+
+  #[test]
+  fn numeric_literals() {
+    let tokens = lex("3.14 -5 +7 1e-9 -2.5E3 .5 #x1F #b1010 #o17 1_000_000 #xFF_FF 6/4").unwrap();
+    let numbers: Vec<String> = tokens.iter().filter_map(|t| match t {
+      Token::Number(n) => {
+        Some(n.print())
+      }
+      _ => {
+        None
       }
+    }).collect();
+    assert_eq!(numbers, ["3.14", "-5", "7", "1e-9", "-2500.0", "0.5", "31", "10", "15", "1000000", "65535", "3/2"]);
+    let signs = lex("- -> + .").unwrap();
+    assert!(signs[..4].iter().all(|t| matches!(t, Token::Ident(_))), "{:?}", signs);
+  }
+
+  #[test]
+  fn malformed_numbers_are_located() {
+    let err = lex("(f\n  1.2.3)").unwrap_err();
+    assert_eq!((err.message.as_str(), err.position.line, err.position.column), ("Malformed number \"1.2.3\"", 2, 3));
+    for source in ["1__0", "1_", "#x1G", "#b102", "1e", "1/0", "#z1", "12abc"] {
+      assert!(lex(source).is_err(), "{} lexed", source);
     }
   }
-}
\ No newline at end of file
+
+  //Offsets count bytes where columns count characters
+  #[test]
+  fn unexpected_characters_are_located() {
+    let err = lex("(list \"é\" ~)").unwrap_err();
+    assert_eq!(err.message, "Unexpected character '~'");
+    assert_eq!(err.position, Position { line: 1, column: 11, offset: 11 });
+    let err = lex("(a\n  |b)").unwrap_err();
+    assert_eq!(err.position, Position { line: 2, column: 3, offset: 5 });
+    assert_eq!(err.to_string(), "Syntax Error at line 2, column 3: Unexpected character '|'");
+    assert!(lex("(x\r\n\ty)").is_ok());
+  }
+
+  #[test]
+  fn unclosed_block_comment_is_an_error() {
+    let err = lex("1 #| oops\n(car 5)").unwrap_err();
+    assert_eq!((err.message.as_str(), err.position.line, err.position.column), ("Unclosed block comment", 1, 3));
+    assert!(lex("#| a #| b |# c").is_err());
+    assert!(lex("#| a #| b |# c |# 1").is_ok());
+  }
+
+  #[test]
+  fn unterminated_strings_are_errors() {
+    let err = lex("(list 1 \"abc").unwrap_err();
+    assert_eq!((err.message.as_str(), err.position.column), ("Unterminated string", 9));
+    let err = lex("\"abc\\").unwrap_err();
+    assert_eq!((err.message.as_str(), err.position.column), ("Malformed escape \\", 5));
+    let err = lex("\"abc\\u{41").unwrap_err();
+    assert_eq!((err.message.as_str(), err.position.column), ("Malformed escape \\u{41", 5));
+    let mut lexer = Lexer::from_string(format!("\"abc"));
+    assert!(lexer.tokenize_partial().is_ok() && !lexer.is_complete());
+  }
+}
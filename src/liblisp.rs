@@ -1,177 +1,1739 @@
-use std::{cell::RefCell, rc::Rc, collections::HashMap, ops::{Add, Div, Mul, Sub}};
+use std::{cell::RefCell, cmp::Ordering, rc::Rc, collections::HashMap, ops::{Add, Div, Mul, Sub}, thread::LocalKey};
 
-use crate::{env::{Executable, ExecutionEnv}};
+use crate::{binlisp, env::{Executable, ExecutionEnv, Params, Tail}, error::{locate, ErrorKind, LispError}, expand::{self, Scope, SyntaxRules}, lex::Span, num::Number};
 
 #[derive(Clone, Debug)]
 pub enum Literal {
   String(String),
-  Num(f64),
-  Atom(String),
-  List(Vec<Literal>),
-  Err(String, i64),
+  Num(Number),
+  //Symbols, lists and collections read from source carry the span they were parsed from
+  Atom(String, Option<Span>),
+  //:name, which evaluates to itself
+  Keyword(String),
+  //An identifier introduced by a syntax-rules template, with the marks of
+  //the expansions that introduced it and the scope of the macro it came from
+  Syntax(String, Vec<usize>, Scope),
+  Bool(bool),
+  Nil,
+  List(Vec<Literal>, Option<Span>),
+  //[a b c], whose forms are evaluated to give the elements
+  Vector(Vec<Literal>, Option<Span>),
+  //{k v ...}, whose values have keys unique under equal? and kept in the order first written
+  Map(Vec<(Literal, Literal)>, Option<Span>),
+  //#{a b c}, whose values have elements unique under equal?
+  Set(Vec<Literal>, Option<Span>),
+  //Builtins and closures as first class values
+  Func(Rc<Executable>),
+  //An error caught by try or passed to a handler
+  Err(Rc<LispError>),
 }
 
 impl Literal {
+	//Prints strings as their contents, as display does
 	pub fn print(&self) -> String{
+		self.render(false)
+	}
+	//Prints strings quoted and escaped, so the output reads back as the same value
+	pub fn write(&self) -> String {
+		self.render(true)
+	}
+	fn render(&self, write: bool) -> String {
 		match self {
+			Literal::String(s) if write => {
+				escape_string(s)
+			}
 			Literal::String(s) => {
 				s.to_string()
 			}
 			Literal::Num(n) => {
-				format!("{}",n)
+				n.print()
+			}
+			Literal::Atom(s, _) => {
+				s.to_string()
+			}
+			Literal::Keyword(k) => {
+				format!(":{}", k)
 			}
-			Literal::Atom(s) => {
+			Literal::Syntax(s, _, _) => {
 				s.to_string()
 			}
-			Literal::Err(s, c) => {
-				format!("{} with code {1}", s, c)
+			Literal::Bool(b) => {
+				format!("{}", b)
+			}
+			Literal::Nil => {
+				format!("nil")
+			}
+			Literal::Func(f) => {
+				match f.as_ref() {
+					Executable::Builtin(_) | Executable::SpecialForm(_) | Executable::Primitive(_) => {
+						format!("#<builtin>")
+					}
+					Executable::LispClosure(args, _, _) => {
+						format!("#<closure ({})>", args.print())
+					}
+					Executable::BytecodeObject(closure) => {
+						format!("#<compiled ({})>", closure.function.lambda_list())
+					}
+					Executable::Macro(args, _, _) => {
+						format!("#<macro ({})>", args.print())
+					}
+					Executable::SyntaxRules(_) => {
+						format!("#<syntax-rules>")
+					}
+				}
+			}
+			Literal::Err(e) => {
+				format!("#<error {}>", e)
+			}
+			Literal::List(l, _) => {
+				format!("({})", l.iter().map(|v| v.render(write)).collect::<Vec<String>>().join(" "))
+			}
+			Literal::Vector(l, _) => {
+				format!("[{}]", l.iter().map(|v| v.render(write)).collect::<Vec<String>>().join(" "))
+			}
+			Literal::Map(pairs, _) => {
+				format!("{{{}}}", pairs.iter().map(|(k, v)| format!("{} {}", k.render(write), v.render(write))).collect::<Vec<String>>().join(" "))
+			}
+			Literal::Set(l, _) => {
+				format!("#{{{}}}", l.iter().map(|v| v.render(write)).collect::<Vec<String>>().join(" "))
+			}
+		}
+	}
+	//Builds a map, where a later value for a key replaces the earlier one
+	pub fn map_from(pairs: Vec<(Literal, Literal)>, span: Option<Span>) -> Literal {
+		let mut out: Vec<(Literal, Literal)> = Vec::new();
+		for (k, v) in pairs {
+			match out.iter_mut().find(|(key, _)| key.is_equal(&k)) {
+				Some(entry) => {
+					entry.1 = v;
+				}
+				None => {
+					out.push((k, v));
+				}
+			}
+		}
+		Literal::Map(out, span)
+	}
+	//Builds a set, dropping repeated elements
+	pub fn set_from(items: Vec<Literal>, span: Option<Span>) -> Literal {
+		let mut out: Vec<Literal> = Vec::new();
+		for item in items {
+			if !out.iter().any(|i| i.is_equal(&item)) {
+				out.push(item);
+			}
+		}
+		Literal::Set(out, span)
+	}
+	//The forms in a vector, map or set, with map keys and values alternating
+	pub fn collection_forms(&self) -> Option<Vec<Literal>> {
+		match self {
+			Literal::Vector(l, _) | Literal::Set(l, _) => {
+				Some(l.clone())
+			}
+			Literal::Map(pairs, _) => {
+				Some(pairs.iter().flat_map(|(k, v)| [k.clone(), v.clone()]).collect())
+			}
+			_ => {
+				None
+			}
+		}
+	}
+	//A collection of the same kind and span as this one holding the given values,
+	//with repeated keys and elements dropped as map_from and set_from do
+	pub fn rebuild_collection(&self, forms: Vec<Literal>) -> Literal {
+		match self {
+			Literal::Map(_, span) => {
+				Literal::map_from(forms.chunks(2).map(|p| (p[0].clone(), p.get(1).cloned().unwrap_or(Literal::Nil))).collect(), *span)
+			}
+			Literal::Set(_, span) => {
+				Literal::set_from(forms, *span)
+			}
+			_ => {
+				Literal::Vector(forms, self.span())
+			}
+		}
+	}
+	//A collection of the same kind and span as this one holding the given forms
+	//as written. Forms that look the same may still evaluate differently, like (rand)
+	pub fn with_forms(&self, forms: Vec<Literal>) -> Literal {
+		match self {
+			Literal::Map(_, span) => {
+				Literal::Map(forms.chunks(2).map(|p| (p[0].clone(), p.get(1).cloned().unwrap_or(Literal::Nil))).collect(), *span)
+			}
+			Literal::Set(_, span) => {
+				Literal::Set(forms, *span)
+			}
+			_ => {
+				Literal::Vector(forms, self.span())
+			}
+		}
+	}
+	//Where a form was read from, if it was read from source
+	pub fn span(&self) -> Option<Span> {
+		match self {
+			Literal::Atom(_, span) | Literal::List(_, span) | Literal::Vector(_, span) | Literal::Map(_, span) | Literal::Set(_, span) => {
+				*span
 			}
-			Literal::List(l) => {
-				l.iter().map(|v| v.print()).fold(format!("("), |acc,s| acc+&s) + ")"
+			_ => {
+				None
 			}
 		}
 	}
 }
 
-impl std::fmt::Display for Literal {
-	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> { write!(f, "{}",self.print()); Ok(())}
+//Quotes a string, escaping the characters the lexer reads escapes for
+fn escape_string(s: &str) -> String {
+	let mut out = String::from("\"");
+	for c in s.chars() {
+		match c {
+			'"' | '\\' => {
+				out.push('\\');
+				out.push(c);
+			}
+			'\n' => {
+				out.push_str("\\n");
+			}
+			'\t' => {
+				out.push_str("\\t");
+			}
+			'\r' => {
+				out.push_str("\\r");
+			}
+			c if c.is_control() => {
+				out.push_str(&format!("\\u{{{:x}}}", c as u32));
+			}
+			c => {
+				out.push(c);
+			}
+		}
+	}
+	out.push('"');
+	out
 }
 
-impl Add for Literal {
-	type Output = Literal;
-	fn add(self, rhs: Self) -> Self::Output {
-		if let Literal::Num(a) = self {
-			if let Literal::Num(b) = rhs {
-				return Literal::Num(a + b)
+impl Literal {
+	//Only false, nil and the empty list count as false in conditionals,
+	//every other value including 0 and "" is true
+	pub fn is_truthy(&self) -> bool {
+		match self {
+			Literal::Bool(b) => {
+				*b
+			}
+			Literal::Nil => {
+				false
+			}
+			Literal::List(l, _) => {
+				!l.is_empty()
+			}
+			_ => {
+				true
+			}
+		}
+	}
+	//Identity comparison, used by eq?
+	//Compound values are only eq? to themselves, which without shared
+	//storage means lists and strings compare equal only when empty
+	pub fn is_eq(&self, other: &Literal) -> bool {
+		match (self, other) {
+			(Literal::Num(a), Literal::Num(b)) => {
+				a.is_eqv(b)
+			}
+			(Literal::Atom(a, _), Literal::Atom(b, _)) | (Literal::Keyword(a), Literal::Keyword(b)) => {
+				a == b
+			}
+			(Literal::Syntax(a, m, _), Literal::Syntax(b, n, _)) => {
+				a == b && m == n
+			}
+			(Literal::Bool(a), Literal::Bool(b)) => {
+				a == b
+			}
+			(Literal::Nil, Literal::Nil) => {
+				true
 			}
-			else {
-				return Literal::Err(format!("Type Error: Addition between non-numeric types not support"), -4)
+			(Literal::String(a), Literal::String(b)) => {
+				a.is_empty() && b.is_empty()
+			}
+			(Literal::List(a, _), Literal::List(b, _)) | (Literal::Vector(a, _), Literal::Vector(b, _)) | (Literal::Set(a, _), Literal::Set(b, _)) => {
+				a.is_empty() && b.is_empty()
+			}
+			(Literal::Map(a, _), Literal::Map(b, _)) => {
+				a.is_empty() && b.is_empty()
+			}
+			(Literal::Func(a), Literal::Func(b)) => {
+				Rc::ptr_eq(a, b)
+			}
+			(Literal::Err(a), Literal::Err(b)) => {
+				Rc::ptr_eq(a, b)
+			}
+			_ => {
+				false
 			}
 		}
-		else {
-			return Literal::Err(format!("Type Error: Addition between non-numeric types not supported"), -4)
+	}
+	//Structural comparison, used by equal?
+	pub fn is_equal(&self, other: &Literal) -> bool {
+		match (self, other) {
+			(Literal::String(a), Literal::String(b)) => {
+				a == b
+			}
+			(Literal::List(a, _), Literal::List(b, _)) | (Literal::Vector(a, _), Literal::Vector(b, _)) => {
+				a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| x.is_equal(y))
+			}
+			//Maps and sets are equal whatever order their entries are in
+			(Literal::Set(a, _), Literal::Set(b, _)) => {
+				a.len() == b.len() && a.iter().all(|x| b.iter().any(|y| x.is_equal(y)))
+			}
+			(Literal::Map(a, _), Literal::Map(b, _)) => {
+				a.len() == b.len() && a.iter().all(|(k, v)| b.iter().any(|(j, w)| k.is_equal(j) && v.is_equal(w)))
+			}
+			_ => {
+				self.is_eq(other)
+			}
 		}
 	}
 }
 
-impl Mul for Literal {
-	type Output = Literal;
-	fn mul(self, rhs: Self) -> Self::Output {
-		if let Literal::Num(a) = self {
-			if let Literal::Num(b) = rhs {
-				return Literal::Num(a * b)
-			}
-			else {
-				return Literal::Err(format!("Type Error: Addition between non-numeric types not support"), -4)
+impl std::fmt::Display for Literal {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> { write!(f, "{}",self.print()) }
+}
+
+//Arithmetic between two numbers, anything else is a type error
+fn arithmetic(lhs: Literal, rhs: Literal, name: &str, op: fn(&Number, &Number) -> Result<Number, String>) -> Result<Literal, LispError> {
+	match (lhs, rhs) {
+		(Literal::Num(a), Literal::Num(b)) => {
+			match op(&a, &b) {
+				Ok(n) => {
+					Ok(Literal::Num(n))
+				}
+				Err(e) => {
+					Err(LispError::new(ErrorKind::ArithmeticError, e))
+				}
 			}
 		}
-		else {
-			return Literal::Err(format!("Type Error: Addition between non-numeric types not supported"), -4)
+		(lhs, rhs) => {
+			let irritants = vec![lhs, rhs].into_iter().filter(|v| !matches!(v, Literal::Num(_))).collect();
+			Err(LispError::about(ErrorKind::TypeError, format!("Operation '{}' between non-numeric types not supported", name), irritants))
 		}
 	}
 }
 
+impl Add for Literal {
+	type Output = Result<Literal, LispError>;
+	fn add(self, rhs: Self) -> Self::Output {
+		arithmetic(self, rhs, "+", |a, b| Ok(a.add(b)))
+	}
+}
+
+impl Mul for Literal {
+	type Output = Result<Literal, LispError>;
+	fn mul(self, rhs: Self) -> Self::Output {
+		arithmetic(self, rhs, "*", |a, b| Ok(a.mul(b)))
+	}
+}
+
 impl Sub for Literal {
-	type Output = Literal;
+	type Output = Result<Literal, LispError>;
 	fn sub(self, rhs: Self) -> Self::Output {
-		if let Literal::Num(a) = self {
-			if let Literal::Num(b) = rhs {
-				return Literal::Num(a - b)
-			}
-			else {
-				return Literal::Err(format!("Type Error: Addition between non-numeric types not support"), -4)
-			}
-		}
-		else {
-			return Literal::Err(format!("Type Error: Addition between non-numeric types not supported"), -4)
-		}
+		arithmetic(self, rhs, "-", |a, b| Ok(a.sub(b)))
 	}
 }
 
 impl Div for Literal {
-	type Output = Literal;
+	type Output = Result<Literal, LispError>;
 	fn div(self, rhs: Self) -> Self::Output {
-		if let Literal::Num(a) = self {
-			if let Literal::Num(b) = rhs {
-				return Literal::Num(a / b)
-			}
-			else {
-				return Literal::Err(format!("Type Error: Addition between non-numeric types not support"), -4)
-			}
-		}
-		else {
-			return Literal::Err(format!("Type Error: Addition between non-numeric types not supported"), -4)
-		}
+		arithmetic(self, rhs, "/", Number::div)
 	}
 }
 
-type LispBuiltin = fn(Rc<RefCell<ExecutionEnv>>, Vec<Literal>) -> Literal;
+type LispBuiltin = fn(Rc<RefCell<ExecutionEnv>>, Vec<Literal>) -> Result<Literal, LispError>;
+//Special forms hand the subform in tail position back to the evaluator
+type LispSpecialForm = fn(Rc<RefCell<ExecutionEnv>>, Vec<Literal>) -> Result<Tail, LispError>;
+//Primitives are plain procedures, called with their arguments already evaluated
+pub type LispPrimitive = fn(Vec<Literal>) -> Result<Literal, LispError>;
 
 pub fn builtins_table() -> HashMap<String, Executable> {
 	HashMap::from([
-		(format!("progn"), Executable::Builtin(0)),
-		(format!("+"), Executable::Builtin(1)),
-		(format!("-"), Executable::Builtin(2)),
-		(format!("*"), Executable::Builtin(3)),
-		(format!("/"), Executable::Builtin(4)),
-		(format!("defn"), Executable::Builtin(5))
+		(format!("progn"), Executable::SpecialForm(0)),
+		(format!("+"), Executable::Primitive(0)),
+		(format!("-"), Executable::Primitive(1)),
+		(format!("*"), Executable::Primitive(2)),
+		(format!("/"), Executable::Primitive(3)),
+		(format!("defn"), Executable::Builtin(0)),
+		(format!("lambda"), Executable::Builtin(1)),
+		(format!("fn"), Executable::Builtin(1)),
+		(format!("if"), Executable::SpecialForm(1)),
+		(format!("cond"), Executable::SpecialForm(2)),
+		(format!("when"), Executable::SpecialForm(3)),
+		(format!("unless"), Executable::SpecialForm(4)),
+		(format!("let"), Executable::SpecialForm(5)),
+		(format!("let*"), Executable::SpecialForm(6)),
+		(format!("letrec"), Executable::SpecialForm(7)),
+		(format!("define"), Executable::Builtin(2)),
+		(format!("set!"), Executable::Builtin(3)),
+		(format!("quote"), Executable::Builtin(4)),
+		(format!("and"), Executable::SpecialForm(8)),
+		(format!("or"), Executable::SpecialForm(9)),
+		(format!("="), Executable::Primitive(4)),
+		(format!("<"), Executable::Primitive(5)),
+		(format!(">"), Executable::Primitive(6)),
+		(format!("<="), Executable::Primitive(7)),
+		(format!(">="), Executable::Primitive(8)),
+		(format!("not"), Executable::Primitive(9)),
+		(format!("eq?"), Executable::Primitive(10)),
+		(format!("equal?"), Executable::Primitive(11)),
+		(format!("quasiquote"), Executable::Builtin(5)),
+		(format!("unquote"), Executable::Builtin(6)),
+		(format!("unquote-splicing"), Executable::Builtin(6)),
+		(format!("defmacro"), Executable::Builtin(7)),
+		(format!("macroexpand-1"), Executable::Builtin(8)),
+		(format!("macroexpand"), Executable::Builtin(9)),
+		(format!("list"), Executable::Primitive(12)),
+		(format!("cons"), Executable::Primitive(13)),
+		(format!("car"), Executable::Primitive(14)),
+		(format!("cdr"), Executable::Primitive(15)),
+		(format!("define-syntax"), Executable::Builtin(10)),
+		(format!("compile"), Executable::Primitive(16)),
+		(format!("disassemble"), Executable::Primitive(17)),
+		(format!("number?"), Executable::Primitive(18)),
+		(format!("integer?"), Executable::Primitive(19)),
+		(format!("rational?"), Executable::Primitive(20)),
+		(format!("exact?"), Executable::Primitive(21)),
+		(format!("inexact?"), Executable::Primitive(22)),
+		(format!("exact->inexact"), Executable::Primitive(23)),
+		(format!("inexact"), Executable::Primitive(23)),
+		(format!("inexact->exact"), Executable::Primitive(24)),
+		(format!("exact"), Executable::Primitive(24)),
+		(format!("numerator"), Executable::Primitive(25)),
+		(format!("denominator"), Executable::Primitive(26)),
+		(format!("quotient"), Executable::Primitive(27)),
+		(format!("remainder"), Executable::Primitive(28)),
+		(format!("vector"), Executable::Primitive(29)),
+		(format!("hash-map"), Executable::Primitive(30)),
+		(format!("hash-set"), Executable::Primitive(31)),
+		(format!("get"), Executable::Primitive(32)),
+		(format!("try"), Executable::Builtin(11)),
+		(format!("handler-bind"), Executable::Builtin(12)),
+		(format!("restart-case"), Executable::Builtin(13)),
+		(format!("raise"), Executable::Primitive(33)),
+		(format!("error"), Executable::Primitive(34)),
+		(format!("error?"), Executable::Primitive(35)),
+		(format!("error-kind"), Executable::Primitive(36)),
+		(format!("error-message"), Executable::Primitive(37)),
+		(format!("error-code"), Executable::Primitive(38)),
+		(format!("error-irritants"), Executable::Primitive(39)),
+		(format!("invoke-restart"), Executable::Primitive(40)),
 		])
 }
 
 pub fn get_builtins() -> Vec<LispBuiltin> {
-	vec![builtin_progn,
-		builtin_add,
+	vec![builtin_fn,
+		builtin_lambda,
+		builtin_define,
+		builtin_set,
+		builtin_quote,
+		builtin_quasiquote,
+		builtin_unquote,
+		builtin_defmacro,
+		builtin_macroexpand_1,
+		builtin_macroexpand,
+		builtin_define_syntax,
+		builtin_try,
+		builtin_handler_bind,
+		builtin_restart_case,
+	]
+}
+
+pub fn get_primitives() -> Vec<LispPrimitive> {
+	vec![builtin_add,
 		builtin_sub,
 		builtin_mul,
 		builtin_div,
-		builtin_fn,
+		builtin_num_eq,
+		builtin_lt,
+		builtin_gt,
+		builtin_le,
+		builtin_ge,
+		builtin_not,
+		builtin_eq,
+		builtin_equal,
+		builtin_list,
+		builtin_cons,
+		builtin_car,
+		builtin_cdr,
+		builtin_compile,
+		builtin_disassemble,
+		builtin_is_number,
+		builtin_is_integer,
+		builtin_is_rational,
+		builtin_is_exact,
+		builtin_is_inexact,
+		builtin_exact_to_inexact,
+		builtin_inexact_to_exact,
+		builtin_numerator,
+		builtin_denominator,
+		builtin_quotient,
+		builtin_remainder,
+		builtin_vector,
+		builtin_hash_map,
+		builtin_hash_set,
+		builtin_get,
+		builtin_raise,
+		builtin_error,
+		builtin_is_error,
+		builtin_error_kind,
+		builtin_error_message,
+		builtin_error_code,
+		builtin_error_irritants,
+		builtin_invoke_restart,
+	]
+}
+
+pub fn get_special_forms() -> Vec<LispSpecialForm> {
+	vec![builtin_progn,
+		builtin_if,
+		builtin_cond,
+		builtin_when,
+		builtin_unless,
+		builtin_let,
+		builtin_let_star,
+		builtin_letrec,
+		builtin_and,
+		builtin_or,
 	]
 }
 
-pub fn builtin_progn(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Literal {
-	params.into_iter().map(|val| ExecutionEnv::evaluate(env.clone(),val)).last().or_else(|| Some(Literal::Err(format!("Form progn requires at least one argument"), -1))).unwrap()
+pub fn builtin_progn(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Tail, LispError> {
+	let mut forms = params;
+	match forms.pop() {
+		Some(last) => {
+			//The first error ends the body, the forms after it never run
+			for val in forms {
+				ExecutionEnv::evaluate(env.clone(), val)?;
+			}
+			Ok(Tail::Eval(env, last))
+		}
+		None => {
+			Err(LispError::new(ErrorKind::InputError, format!("form 'progn' requires at least one argument")))
+		}
+	}
 }
 
-pub fn builtin_add(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Literal {
-	params.into_iter().fold(Literal::Num(0.0), |acc, x| acc + ExecutionEnv::evaluate(env.clone(),x))
+pub fn builtin_add(params: Vec<Literal>) -> Result<Literal, LispError> {
+	params.into_iter().try_fold(Literal::Num(Number::Fixnum(0)), |acc, x| acc + x)
 }
 
-pub fn builtin_sub(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Literal {
+pub fn builtin_sub(params: Vec<Literal>) -> Result<Literal, LispError> {
 	if params.len() != 2 {
-		return Literal::Err(format!("Input Error: Operation '-' requires 2 arguments"), -5)
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation '-' requires 2 arguments")))
 	}
-	let mut params = params.into_iter().map(|v| ExecutionEnv::evaluate(env.clone(),v));
+	let mut params = params.into_iter();
 	params.next().unwrap() - params.next().unwrap()
 }
 
-pub fn builtin_div(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Literal {
+pub fn builtin_div(params: Vec<Literal>) -> Result<Literal, LispError> {
 	if params.len() != 2 {
-		return Literal::Err(format!("Input Error: Operation '/' requires 2 arguments"), -5)
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation '/' requires 2 arguments")))
 	}
-	let mut params = params.into_iter().map(|v| ExecutionEnv::evaluate(env.clone(),v));
+	let mut params = params.into_iter();
 	params.next().unwrap() / params.next().unwrap()
 }
 
-pub fn builtin_mul(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Literal {
-	params.into_iter().fold(Literal::Num(1.0), |acc, x| acc * ExecutionEnv::evaluate(env.clone(),x))
+pub fn builtin_mul(params: Vec<Literal>) -> Result<Literal, LispError> {
+	params.into_iter().try_fold(Literal::Num(Number::Fixnum(1)), |acc, x| acc * x)
 }
 
-pub fn builtin_fn(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Literal {
+pub fn builtin_fn(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Literal, LispError> {
 	if params.len() != 3 {
-		return Literal::Err(format!("Input Error: form 'fn' requires 3 arguments"), -5)
+		return Err(LispError::new(ErrorKind::InputError, format!("form 'defn' requires 3 arguments")))
 	}
 	let mut param_entries = params.into_iter();
 	let name = param_entries.next().unwrap();
-	let args = param_entries.next().unwrap();
-	let arg_names = match args {
-		Literal::List(l) => {
-			l.into_iter().map(|a| a.print()).collect()
+	let arg_names = Params { name: name.print(), ..Params::parse(&param_entries.next().unwrap(), "defn")? };
+	let body = param_entries.next().unwrap();
+	env.borrow_mut().add_function(name.print(), Executable::LispClosure(arg_names, body, env.clone()));
+	Ok(name)
+}
+
+pub fn builtin_lambda(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 2 {
+		return Err(LispError::new(ErrorKind::InputError, format!("form 'lambda' requires 2 arguments")))
+	}
+	let mut param_entries = params.into_iter();
+	let arg_names = Params::parse(&param_entries.next().unwrap(), "lambda")?;
+	let body = param_entries.next().unwrap();
+	Ok(Literal::Func(Rc::new(Executable::LispClosure(arg_names, body, env.clone()))))
+}
+
+pub fn builtin_if(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Tail, LispError> {
+	if params.len() != 2 && params.len() != 3 {
+		return Err(LispError::new(ErrorKind::InputError, format!("form 'if' requires 2 or 3 arguments")))
+	}
+	let mut param_entries = params.into_iter();
+	let test = ExecutionEnv::evaluate(env.clone(), param_entries.next().unwrap())?;
+	let then = param_entries.next().unwrap();
+	if test.is_truthy() {
+		Ok(Tail::Eval(env, then))
+	}
+	else {
+		match param_entries.next() {
+			Some(otherwise) => {
+				Ok(Tail::Eval(env, otherwise))
+			}
+			None => {
+				Ok(Tail::Done(Literal::Nil))
+			}
+		}
+	}
+}
+
+pub fn builtin_cond(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Tail, LispError> {
+	for clause in params {
+		let mut forms = match clause {
+			Literal::List(l, _) if !l.is_empty() => {
+				l.into_iter()
+			}
+			_ => {
+				return Err(LispError::new(ErrorKind::InputError, format!("form 'cond' requires each clause to be a non-empty list")))
+			}
+		};
+		let test = match forms.next().unwrap() {
+			Literal::Atom(s, _) if s == "else" => {
+				Literal::Atom(s, None)
+			}
+			t => {
+				ExecutionEnv::evaluate(env.clone(), t)?
+			}
+		};
+		if test.is_truthy() {
+			let body: Vec<Literal> = forms.collect();
+			if body.is_empty() {
+				return Ok(Tail::Done(test));
+			}
+			return builtin_progn(env, body);
+		}
+	}
+	Ok(Tail::Done(Literal::Nil))
+}
+
+pub fn builtin_when(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Tail, LispError> {
+	conditional_body(env, params, true, "when")
+}
+
+pub fn builtin_unless(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Tail, LispError> {
+	conditional_body(env, params, false, "unless")
+}
+
+//Shared by when and unless, runs the body if the test's truthiness matches expected
+fn conditional_body(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>, expected: bool, form: &str) -> Result<Tail, LispError> {
+	if params.len() < 2 {
+		return Err(LispError::new(ErrorKind::InputError, format!("form '{}' requires a test and at least one body form", form)))
+	}
+	let mut param_entries = params.into_iter();
+	let test = ExecutionEnv::evaluate(env.clone(), param_entries.next().unwrap())?;
+	if test.is_truthy() == expected {
+		builtin_progn(env, param_entries.collect())
+	}
+	else {
+		Ok(Tail::Done(Literal::Nil))
+	}
+}
+
+//(name, init form) pairs from a let binding list
+type Bindings = Vec<(String, Literal)>;
+
+fn let_bindings(bindings: Literal, form: &str) -> Result<Bindings, LispError> {
+	let err = || LispError::new(ErrorKind::InputError, format!("form '{}' requires a list of (name value) bindings", form));
+	match bindings {
+		Literal::List(l, _) => {
+			l.into_iter().map(|b| {
+				match b {
+					Literal::List(pair, _) if pair.len() == 2 => {
+						let mut pair = pair.into_iter();
+						match pair.next().unwrap() {
+							Literal::Atom(name, _) => {
+								Ok((name, pair.next().unwrap()))
+							}
+							_ => {
+								Err(err())
+							}
+						}
+					}
+					_ => {
+						Err(err())
+					}
+				}
+			}).collect()
+		}
+		_ => {
+			Err(err())
+		}
+	}
+}
+
+//Separates the binding list of a let form from its body
+pub fn let_parts(params: Vec<Literal>, form: &str) -> Result<(Bindings, Vec<Literal>), LispError> {
+	if params.len() < 2 {
+		return Err(LispError::new(ErrorKind::InputError, format!("form '{}' requires bindings and at least one body form", form)))
+	}
+	let mut param_entries = params.into_iter();
+	let bindings = let_bindings(param_entries.next().unwrap(), form)?;
+	Ok((bindings, param_entries.collect()))
+}
+
+pub fn builtin_let(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Tail, LispError> {
+	let (bindings, body) = let_parts(params, "let")?;
+	//Every initializer sees only the enclosing scope
+	let mut vals = HashMap::new();
+	for (name, init) in bindings {
+		let val = ExecutionEnv::evaluate(env.clone(), init)?;
+		vals.insert(name, val);
+	}
+	builtin_progn(ExecutionEnv::extend(env, vals), body)
+}
+
+pub fn builtin_let_star(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Tail, LispError> {
+	let (bindings, body) = let_parts(params, "let*")?;
+	//Each initializer sees the bindings made before it
+	let scope = ExecutionEnv::extend(env, HashMap::new());
+	for (name, init) in bindings {
+		let val = ExecutionEnv::evaluate(scope.clone(), init)?;
+		scope.borrow_mut().define(name, val);
+	}
+	builtin_progn(scope, body)
+}
+
+pub fn builtin_letrec(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Tail, LispError> {
+	let (bindings, body) = let_parts(params, "letrec")?;
+	//All names are in scope, as nil, before any initializer runs
+	//so lambdas can refer to each other
+	let scope = ExecutionEnv::extend(env, bindings.iter().map(|(name, _)| (name.clone(), Literal::Nil)).collect());
+	for (name, init) in bindings {
+		let val = ExecutionEnv::evaluate(scope.clone(), init)?;
+		scope.borrow_mut().define(name, val);
+	}
+	builtin_progn(scope, body)
+}
+
+pub fn builtin_define(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 2 {
+		return Err(LispError::new(ErrorKind::InputError, format!("form 'define' requires 2 arguments")))
+	}
+	let mut param_entries = params.into_iter();
+	match param_entries.next().unwrap() {
+		Literal::Atom(name, _) => {
+			let val = ExecutionEnv::evaluate(env.clone(), param_entries.next().unwrap())?;
+			env.borrow_mut().define(name.clone(), val);
+			Ok(Literal::Atom(name, None))
+		}
+		//(define (name args...) body) is shorthand for defn
+		Literal::List(l, _) if !l.is_empty() => {
+			let mut signature = l.into_iter();
+			let name = signature.next().unwrap();
+			builtin_fn(env, vec![name, Literal::List(signature.collect(), None), param_entries.next().unwrap()])
+		}
+		_ => {
+			Err(LispError::new(ErrorKind::InputError, format!("form 'define' requires a name")))
+		}
+	}
+}
+
+pub fn builtin_set(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 2 {
+		return Err(LispError::new(ErrorKind::InputError, format!("form 'set!' requires 2 arguments")))
+	}
+	let mut param_entries = params.into_iter();
+	let (name, scope) = match param_entries.next().unwrap() {
+		Literal::Atom(name, _) => {
+			(name, env.clone())
+		}
+		Literal::Syntax(name, _, scope) => {
+			(name, scope.0)
 		}
 		_ => {
-			return Literal::Err(format!("Input Error: form 'fn' requires list of argument names"), -5)
+			return Err(LispError::new(ErrorKind::InputError, format!("form 'set!' requires a symbol")))
 		}
 	};
-	let body = param_entries.next().unwrap();
-	env.borrow_mut().add_function(name.print(), Executable::LispClosure(arg_names, body));
-	name
-}
\ No newline at end of file
+	let val = ExecutionEnv::evaluate(env, param_entries.next().unwrap())?;
+	scope.borrow_mut().set(&name, val.clone())?;
+	Ok(val)
+}
+
+pub fn builtin_quote(_env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("form 'quote' requires 1 argument")))
+	}
+	Ok(params.into_iter().next().unwrap())
+}
+
+//and/or stop evaluating as soon as the result is known
+//The last operand is in tail position
+pub fn builtin_and(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Tail, LispError> {
+	let mut operands = params;
+	let last = match operands.pop() {
+		Some(last) => {
+			last
+		}
+		None => {
+			return Ok(Tail::Done(Literal::Bool(true)))
+		}
+	};
+	for p in operands {
+		let val = ExecutionEnv::evaluate(env.clone(), p)?;
+		if !val.is_truthy() {
+			return Ok(Tail::Done(val));
+		}
+	}
+	Ok(Tail::Eval(env, last))
+}
+
+pub fn builtin_or(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Tail, LispError> {
+	let mut operands = params;
+	let last = match operands.pop() {
+		Some(last) => {
+			last
+		}
+		None => {
+			return Ok(Tail::Done(Literal::Bool(false)))
+		}
+	};
+	for p in operands {
+		let val = ExecutionEnv::evaluate(env.clone(), p)?;
+		if val.is_truthy() {
+			return Ok(Tail::Done(val));
+		}
+	}
+	Ok(Tail::Eval(env, last))
+}
+
+//Numeric comparisons hold when every adjacent pair of arguments satisfies op
+fn compare_chain(params: Vec<Literal>, name: &str, op: fn(Ordering) -> bool) -> Result<Literal, LispError> {
+	if params.is_empty() {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation '{}' requires at least 1 argument", name)))
+	}
+	let mut nums = Vec::new();
+	for v in params {
+		match v {
+			Literal::Num(n) => {
+				nums.push(n);
+			}
+			other => {
+				return Err(LispError::about(ErrorKind::TypeError, format!("Comparison '{}' between non-numeric types not supported", name), vec![other]))
+			}
+		}
+	}
+	//Nothing is ordered against NaN, so every comparison with it is false
+	Ok(Literal::Bool(nums.windows(2).all(|w| w[0].compare(&w[1]).is_some_and(op))))
+}
+
+pub fn builtin_num_eq(params: Vec<Literal>) -> Result<Literal, LispError> {
+	compare_chain(params, "=", Ordering::is_eq)
+}
+
+pub fn builtin_lt(params: Vec<Literal>) -> Result<Literal, LispError> {
+	compare_chain(params, "<", Ordering::is_lt)
+}
+
+pub fn builtin_gt(params: Vec<Literal>) -> Result<Literal, LispError> {
+	compare_chain(params, ">", Ordering::is_gt)
+}
+
+pub fn builtin_le(params: Vec<Literal>) -> Result<Literal, LispError> {
+	compare_chain(params, "<=", Ordering::is_le)
+}
+
+pub fn builtin_ge(params: Vec<Literal>) -> Result<Literal, LispError> {
+	compare_chain(params, ">=", Ordering::is_ge)
+}
+
+pub fn builtin_not(params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation 'not' requires 1 argument")))
+	}
+	Ok(Literal::Bool(!params[0].is_truthy()))
+}
+
+//Shared by eq? and equal?, which both compare exactly two values
+fn compare_pair(params: Vec<Literal>, name: &str, op: fn(&Literal, &Literal) -> bool) -> Result<Literal, LispError> {
+	if params.len() != 2 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation '{}' requires 2 arguments", name)))
+	}
+	Ok(Literal::Bool(op(&params[0], &params[1])))
+}
+
+pub fn builtin_eq(params: Vec<Literal>) -> Result<Literal, LispError> {
+	compare_pair(params, "eq?", Literal::is_eq)
+}
+
+pub fn builtin_equal(params: Vec<Literal>) -> Result<Literal, LispError> {
+	compare_pair(params, "equal?", Literal::is_equal)
+}
+
+pub fn builtin_quasiquote(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("form 'quasiquote' requires 1 argument")))
+	}
+	quasi_expand(env, params.into_iter().next().unwrap(), 1)
+}
+
+pub fn builtin_unquote(_env: Rc<RefCell<ExecutionEnv>>, _params: Vec<Literal>) -> Result<Literal, LispError> {
+	Err(LispError::new(ErrorKind::InputError, format!("unquote used outside of quasiquote")))
+}
+
+//Matches a two element form like (unquote x), returning its head and argument
+fn quote_form(l: &[Literal]) -> Option<(&str, &Literal)> {
+	match l {
+		[Literal::Atom(name, _), arg] => {
+			Some((name.as_str(), arg))
+		}
+		_ => {
+			None
+		}
+	}
+}
+
+//Fills in a quasiquote template, depth counts the enclosing quasiquotes
+//so only unquotes belonging to the outermost one are evaluated
+fn quasi_expand(env: Rc<RefCell<ExecutionEnv>>, template: Literal, depth: usize) -> Result<Literal, LispError> {
+	let (l, span) = match template {
+		Literal::List(l, span) => {
+			(l, span)
+		}
+		other => {
+			return Ok(other)
+		}
+	};
+	if let Some((name, arg)) = quote_form(&l) {
+		let nested = match name {
+			"unquote" if depth == 1 => {
+				return ExecutionEnv::evaluate(env, arg.clone())
+			}
+			//There's no list here for the values to be spliced into
+			"unquote-splicing" if depth == 1 => {
+				return Err(locate(LispError::new(ErrorKind::SyntaxError, format!("unquote-splicing must be inside a list")), span))
+			}
+			"unquote" | "unquote-splicing" => {
+				Some(depth - 1)
+			}
+			"quasiquote" => {
+				Some(depth + 1)
+			}
+			_ => {
+				None
+			}
+		};
+		if let Some(d) = nested {
+			let inner = quasi_expand(env, arg.clone(), d)?;
+			return Ok(Literal::List(vec![Literal::Atom(name.to_string(), None), inner], span))
+		}
+	}
+	let mut out = Vec::new();
+	for item in l {
+		if let Literal::List(inner, _) = &item {
+			if let Some(("unquote-splicing", arg)) = quote_form(inner) {
+				if depth == 1 {
+					match ExecutionEnv::evaluate(env.clone(), arg.clone())? {
+						Literal::List(spliced, _) => {
+							out.extend(spliced);
+						}
+						Literal::Nil => {}
+						v => {
+							return Err(LispError::about(ErrorKind::TypeError, format!("unquote-splicing requires a list"), vec![v]))
+						}
+					}
+					continue;
+				}
+			}
+		}
+		out.push(quasi_expand(env.clone(), item, depth)?);
+	}
+	Ok(Literal::List(out, span))
+}
+
+pub fn builtin_defmacro(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 3 {
+		return Err(LispError::new(ErrorKind::InputError, format!("form 'defmacro' requires 3 arguments")))
+	}
+	let mut param_entries = params.into_iter();
+	let name = param_entries.next().unwrap();
+	let arg_names = Params { name: name.print(), ..Params::parse(&param_entries.next().unwrap(), "defmacro")? };
+	let body = param_entries.next().unwrap();
+	env.borrow_mut().add_function(name.print(), Executable::Macro(arg_names, body, env.clone()));
+	Ok(name)
+}
+
+//Expands form once if it is a macro call, also reporting whether it was
+fn expand_once(env: &Rc<RefCell<ExecutionEnv>>, form: Literal) -> Result<(Literal, bool), LispError> {
+	if let Some(exec) = expand::syntax_rules_for(env, &form) {
+		if let Executable::SyntaxRules(rules) = exec.as_ref() {
+			return Ok((expand::expand_use(rules, &form)?, true));
+		}
+	}
+	let mac = env.borrow().macro_for(&form);
+	match (mac, form) {
+		(Some(mac), Literal::List(l, _)) => {
+			Ok((mac.expand(l.into_iter().skip(1).collect())?, true))
+		}
+		(_, form) => {
+			Ok((form, false))
+		}
+	}
+}
+
+//Shared by the macroexpand builtins, which evaluate their argument to get the form
+fn macroexpand_arg(env: &Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>, name: &str) -> Result<Literal, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation '{}' requires 1 argument", name)))
+	}
+	ExecutionEnv::evaluate(env.clone(), params.into_iter().next().unwrap())
+}
+
+pub fn builtin_macroexpand_1(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Literal, LispError> {
+	let form = macroexpand_arg(&env, params, "macroexpand-1")?;
+	Ok(expand_once(&env, form)?.0)
+}
+
+pub fn builtin_macroexpand(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Literal, LispError> {
+	let mut form = macroexpand_arg(&env, params, "macroexpand")?;
+	loop {
+		match expand_once(&env, form)? {
+			(expanded, true) => {
+				form = expanded;
+			}
+			(expanded, false) => {
+				return Ok(expanded);
+			}
+		}
+	}
+}
+
+//List primitives, mostly so macros can take apart the forms they receive
+pub fn builtin_list(params: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(Literal::List(params, None))
+}
+
+pub fn builtin_cons(params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 2 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation 'cons' requires 2 arguments")))
+	}
+	let mut vals = params.into_iter();
+	let head = vals.next().unwrap();
+	match vals.next().unwrap() {
+		Literal::List(mut l, _) => {
+			l.insert(0, head);
+			Ok(Literal::List(l, None))
+		}
+		Literal::Nil => {
+			Ok(Literal::List(vec![head], None))
+		}
+		_ => {
+			Err(LispError::new(ErrorKind::TypeError, format!("Operation 'cons' requires a list as its second argument")))
+		}
+	}
+}
+
+//Checks the single list argument of car and cdr
+fn list_arg(params: Vec<Literal>, name: &str) -> Result<Vec<Literal>, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation '{}' requires 1 argument", name)))
+	}
+	match params.into_iter().next().unwrap() {
+		Literal::List(l, _) => {
+			Ok(l)
+		}
+		Literal::Nil => {
+			Ok(Vec::new())
+		}
+		other => {
+			Err(LispError::about(ErrorKind::TypeError, format!("Operation '{}' requires a list", name), vec![other]))
+		}
+	}
+}
+
+pub fn builtin_car(params: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(list_arg(params, "car")?.into_iter().next().unwrap_or(Literal::Nil))
+}
+
+pub fn builtin_cdr(params: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(Literal::List(list_arg(params, "cdr")?.into_iter().skip(1).collect(), None))
+}
+
+pub fn builtin_define_syntax(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 2 {
+		return Err(LispError::new(ErrorKind::InputError, format!("form 'define-syntax' requires 2 arguments")))
+	}
+	let mut param_entries = params.into_iter();
+	let name = param_entries.next().unwrap();
+	let rules = SyntaxRules::from_spec(param_entries.next().unwrap(), &env)?;
+	env.borrow_mut().add_function(name.print(), Executable::SyntaxRules(rules));
+	Ok(name)
+}
+
+//Compiles a closure to bytecode, closures the compiler can't handle are an error
+pub fn builtin_compile(params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation 'compile' requires 1 argument")))
+	}
+	if let Literal::Func(exec) = &params[0] {
+		match exec.as_ref() {
+			Executable::LispClosure(args, body, scope) => {
+				return match binlisp::compile_closure(args, body, scope) {
+					Ok(closure) => {
+						Ok(Literal::Func(Rc::new(Executable::BytecodeObject(closure))))
+					}
+					Err(e) => {
+						Err(LispError::new(ErrorKind::CompileError, e))
+					}
+				}
+			}
+			Executable::BytecodeObject(_) => {
+				return Ok(params[0].clone())
+			}
+			_ => {}
+		}
+	}
+	Err(LispError::new(ErrorKind::TypeError, format!("Operation 'compile' requires a closure")))
+}
+
+pub fn builtin_disassemble(params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation 'disassemble' requires 1 argument")))
+	}
+	if let Literal::Func(exec) = &params[0] {
+		if let Executable::BytecodeObject(closure) = exec.as_ref() {
+			return Ok(Literal::String(closure.function.disassemble()))
+		}
+	}
+	Err(LispError::new(ErrorKind::TypeError, format!("Operation 'disassemble' requires a compiled function")))
+}
+
+//The single argument of a numeric predicate or conversion
+fn number_arg(params: Vec<Literal>, name: &str) -> Result<Number, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation '{}' requires 1 argument", name)))
+	}
+	match params.into_iter().next().unwrap() {
+		Literal::Num(n) => {
+			Ok(n)
+		}
+		other => {
+			Err(LispError::about(ErrorKind::TypeError, format!("Operation '{}' requires a number", name), vec![other]))
+		}
+	}
+}
+
+//Runs a numeric conversion, reporting a number it can't handle
+fn convert_number(params: Vec<Literal>, name: &str, op: fn(&Number) -> Option<Number>) -> Result<Literal, LispError> {
+	let n = number_arg(params, name)?;
+	match op(&n) {
+		Some(v) => {
+			Ok(Literal::Num(v))
+		}
+		None => {
+			Err(LispError::new(ErrorKind::ArithmeticError, format!("{} has no exact value", n.print())))
+		}
+	}
+}
+
+pub fn builtin_is_number(params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation 'number?' requires 1 argument")))
+	}
+	Ok(Literal::Bool(matches!(params[0], Literal::Num(_))))
+}
+
+pub fn builtin_is_integer(params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation 'integer?' requires 1 argument")))
+	}
+	Ok(Literal::Bool(matches!(&params[0], Literal::Num(n) if n.is_integer())))
+}
+
+//Every finite number is a ratio of integers
+pub fn builtin_is_rational(params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation 'rational?' requires 1 argument")))
+	}
+	Ok(Literal::Bool(matches!(&params[0], Literal::Num(n) if n.to_f64().is_finite() || n.is_exact())))
+}
+
+pub fn builtin_is_exact(params: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(Literal::Bool(number_arg(params, "exact?")?.is_exact()))
+}
+
+pub fn builtin_is_inexact(params: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(Literal::Bool(!number_arg(params, "inexact?")?.is_exact()))
+}
+
+pub fn builtin_exact_to_inexact(params: Vec<Literal>) -> Result<Literal, LispError> {
+	convert_number(params, "exact->inexact", |n| Some(n.to_inexact()))
+}
+
+pub fn builtin_inexact_to_exact(params: Vec<Literal>) -> Result<Literal, LispError> {
+	convert_number(params, "inexact->exact", Number::to_exact)
+}
+
+pub fn builtin_numerator(params: Vec<Literal>) -> Result<Literal, LispError> {
+	convert_number(params, "numerator", Number::numerator)
+}
+
+pub fn builtin_denominator(params: Vec<Literal>) -> Result<Literal, LispError> {
+	convert_number(params, "denominator", Number::denominator)
+}
+
+//Shared by quotient and remainder, which divide two integers
+fn integer_division(params: Vec<Literal>, name: &str) -> Result<(Number, Number), LispError> {
+	if params.len() != 2 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation '{}' requires 2 arguments", name)))
+	}
+	match (&params[0], &params[1]) {
+		(Literal::Num(a), Literal::Num(b)) => {
+			a.quotient(b).map_err(|e| LispError::new(ErrorKind::ArithmeticError, format!("Operation '{}' {}", name, e)))
+		}
+		_ => {
+			Err(LispError::new(ErrorKind::TypeError, format!("Operation '{}' requires integers", name)))
+		}
+	}
+}
+
+pub fn builtin_quotient(params: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(Literal::Num(integer_division(params, "quotient")?.0))
+}
+
+pub fn builtin_remainder(params: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(Literal::Num(integer_division(params, "remainder")?.1))
+}
+
+pub fn builtin_vector(params: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(Literal::Vector(params, None))
+}
+
+pub fn builtin_hash_map(params: Vec<Literal>) -> Result<Literal, LispError> {
+	if !params.len().is_multiple_of(2) {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation 'hash-map' requires an even number of arguments")))
+	}
+	Ok(Literal::Map(Vec::new(), None).rebuild_collection(params))
+}
+
+pub fn builtin_hash_set(params: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(Literal::set_from(params, None))
+}
+
+//(get coll key [default]) looks up a map key, a set element or a vector index,
+//giving default or nil when it is missing
+pub fn builtin_get(params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 2 && params.len() != 3 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation 'get' requires 2 or 3 arguments")))
+	}
+	let default = params.get(2).cloned().unwrap_or(Literal::Nil);
+	let found = match (&params[0], &params[1]) {
+		(Literal::Map(pairs, _), key) => {
+			pairs.iter().find(|(k, _)| k.is_equal(key)).map(|(_, v)| v.clone())
+		}
+		(Literal::Set(items, _), key) => {
+			items.iter().find(|i| i.is_equal(key)).cloned()
+		}
+		(Literal::Vector(items, _), Literal::Num(Number::Fixnum(i))) => {
+			Some(*i).filter(|i| *i >= 0).and_then(|i| items.get(i as usize)).cloned()
+		}
+		(Literal::Vector(_, _), Literal::Num(n)) if n.is_integer() => {
+			None
+		}
+		(Literal::Vector(_, _), _) => {
+			return Err(LispError::new(ErrorKind::TypeError, format!("Operation 'get' requires an integer index into a vector")))
+		}
+		_ => {
+			return Err(LispError::new(ErrorKind::TypeError, format!("Operation 'get' requires a map, set or vector")))
+		}
+	};
+	Ok(found.unwrap_or(default))
+}
+
+//A handler in force while the body of a handler-bind or try runs
+#[derive(Clone)]
+enum Handler {
+	//A handler-bind function, called for errors of its kind or of any kind for None
+	Bind(Option<ErrorKind>, Rc<Executable>),
+	//The kinds a try's catch clauses are for, no handler outside
+	//the try sees an error one of them will catch
+	Catch(Vec<Option<ErrorKind>>),
+}
+
+thread_local! {
+	//Both innermost last
+	static HANDLERS: RefCell<Vec<Handler>> = const { RefCell::new(Vec::new()) };
+	static RESTARTS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+//Runs body with entries added to a handler or restart stack,
+//removing them again however it finishes, panicking included
+fn in_force<T, R>(stack: &'static LocalKey<RefCell<Vec<T>>>, added: Vec<T>, body: impl FnOnce() -> R) -> R {
+	let depth = stack.with(|s| {
+		let mut s = s.borrow_mut();
+		let depth = s.len();
+		s.extend(added);
+		depth
+	});
+	let _restore = Restore { stack, depth };
+	body()
+}
+
+//Truncates a stack back to depth when dropped
+struct Restore<T: 'static> {
+	stack: &'static LocalKey<RefCell<Vec<T>>>,
+	depth: usize,
+}
+
+impl<T> Drop for Restore<T> {
+	fn drop(&mut self) {
+		self.stack.with(|s| s.borrow_mut().truncate(self.depth));
+	}
+}
+
+//Runs the handlers in force for an error, innermost first and each with only
+//the handlers outside it in force. A handler declines by returning, or takes
+//over by raising or invoking a restart, and the error that leaves it is the
+//one that carries on unwinding. This happens as the error leaves the innermost
+//handler-bind or restart-case, so the restarts in force there still are
+fn signal(env: &Rc<RefCell<ExecutionEnv>>, mut err: LispError) -> LispError {
+	if err.signaled || err.restart.is_some() {
+		return err;
+	}
+	err.signaled = true;
+	let handlers = HANDLERS.with(|h| h.borrow().clone());
+	let mut raised = None;
+	for (i, handler) in handlers.iter().enumerate().rev() {
+		match handler {
+			Handler::Catch(kinds) if kinds.iter().any(|k| handles(*k, &err)) => {
+				break;
+			}
+			Handler::Bind(kind, func) if handles(*kind, &err) => {
+				HANDLERS.with(|h| h.borrow_mut().truncate(i));
+				if let Err(e) = func.apply(env.clone(), vec![err.condition()]) {
+					raised = Some(signal(env, e));
+					break;
+				}
+			}
+			_ => {}
+		}
+	}
+	HANDLERS.with(|h| *h.borrow_mut() = handlers);
+	raised.unwrap_or(err)
+}
+
+fn handles(kind: Option<ErrorKind>, err: &LispError) -> bool {
+	kind.is_none_or(|k| k == err.kind)
+}
+
+//The kind named at the start of a catch or handler-bind clause,
+//where error stands for every kind
+fn kind_filter(spec: &Literal, form: &str) -> Result<Option<ErrorKind>, LispError> {
+	match spec {
+		Literal::Atom(name, _) if name == "error" => {
+			Ok(None)
+		}
+		Literal::Atom(name, _) if ErrorKind::from_name(name).is_some() => {
+			Ok(ErrorKind::from_name(name))
+		}
+		_ => {
+			Err(LispError::about(ErrorKind::InputError, format!("form '{}' requires an error kind", form), vec![spec.clone()]))
+		}
+	}
+}
+
+//Evaluates forms in order outside tail position, giving the last value
+fn evaluate_body(env: &Rc<RefCell<ExecutionEnv>>, forms: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(ExecutionEnv::evaluate_all(env, forms)?.pop().unwrap_or(Literal::Nil))
+}
+
+struct CatchClause {
+	kind: Option<ErrorKind>,
+	var: String,
+	body: Vec<Literal>,
+}
+
+//Parses (catch [kind] (e) handler...)
+fn catch_clause(forms: Vec<Literal>) -> Result<CatchClause, LispError> {
+	let err = || LispError::new(ErrorKind::InputError, format!("form 'try' requires catch clauses like (catch [kind] (e) handler...)"));
+	let mut forms = forms.into_iter().skip(1).peekable();
+	let kind = match forms.peek() {
+		Some(spec @ Literal::Atom(_, _)) => {
+			let kind = kind_filter(spec, "try")?;
+			forms.next();
+			kind
+		}
+		_ => {
+			None
+		}
+	};
+	match forms.next() {
+		Some(Literal::List(var, _)) if matches!(var.as_slice(), [Literal::Atom(_, _)]) => {
+			Ok(CatchClause { kind, var: var[0].print(), body: forms.collect() })
+		}
+		_ => {
+			Err(err())
+		}
+	}
+}
+
+//The body, catch clauses and cleanup of a try form
+type TryParts = (Vec<Literal>, Vec<CatchClause>, Vec<Literal>);
+
+fn try_parts(params: Vec<Literal>) -> Result<TryParts, LispError> {
+	let mut body = Vec::new();
+	let mut catches = Vec::new();
+	let mut cleanup = None;
+	for form in params {
+		if cleanup.is_some() {
+			return Err(LispError::new(ErrorKind::InputError, format!("form 'try' requires finally to be its last clause")))
+		}
+		match form {
+			Literal::List(l, _) if matches!(l.first(), Some(Literal::Atom(head, _)) if head == "catch") => {
+				catches.push(catch_clause(l)?);
+			}
+			Literal::List(l, _) if matches!(l.first(), Some(Literal::Atom(head, _)) if head == "finally") => {
+				cleanup = Some(l.into_iter().skip(1).collect());
+			}
+			_ if !catches.is_empty() => {
+				return Err(LispError::new(ErrorKind::InputError, format!("form 'try' requires its body before its catch clauses")))
+			}
+			form => {
+				body.push(form);
+			}
+		}
+	}
+	Ok((body, catches, cleanup.unwrap_or_default()))
+}
+
+//(try body... (catch [kind] (e) handler...)... (finally cleanup...))
+//An error in the body runs the first catch clause for its kind, with e bound
+//to what was raised. The cleanup runs however the rest finishes, and an error
+//in it replaces theirs. Restarts unwind through without being caught
+pub fn builtin_try(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Literal, LispError> {
+	let (body, catches, cleanup) = try_parts(params)?;
+	let kinds = catches.iter().map(|c| c.kind).collect();
+	let result = match in_force(&HANDLERS, vec![Handler::Catch(kinds)], || evaluate_body(&env, body)) {
+		Err(e) if e.restart.is_none() => {
+			match catches.into_iter().find(|c| handles(c.kind, &e)) {
+				Some(clause) => {
+					let scope = ExecutionEnv::extend(env.clone(), HashMap::from([(clause.var, e.condition())]));
+					evaluate_body(&scope, clause.body)
+				}
+				None => {
+					Err(e)
+				}
+			}
+		}
+		result => {
+			result
+		}
+	};
+	evaluate_body(&env, cleanup)?;
+	result
+}
+
+//(handler-bind ((kind handler)...) body...)
+//Each handler is called with the condition before the error unwinds past
+//the handler-bind, and the first binding for a kind is tried first
+pub fn builtin_handler_bind(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Literal, LispError> {
+	let err = || LispError::new(ErrorKind::InputError, format!("form 'handler-bind' requires a list of (kind handler) bindings"));
+	let mut param_entries = params.into_iter();
+	let bindings = match param_entries.next() {
+		Some(Literal::List(l, _)) => {
+			l
+		}
+		Some(Literal::Nil) => {
+			Vec::new()
+		}
+		_ => {
+			return Err(err())
+		}
+	};
+	let mut handlers = Vec::new();
+	for binding in bindings {
+		match binding {
+			Literal::List(pair, _) if pair.len() == 2 => {
+				let kind = kind_filter(&pair[0], "handler-bind")?;
+				match ExecutionEnv::evaluate(env.clone(), pair[1].clone())? {
+					Literal::Func(func) => {
+						handlers.push(Handler::Bind(kind, func));
+					}
+					other => {
+						return Err(LispError::about(ErrorKind::TypeError, format!("form 'handler-bind' requires each handler to be a function"), vec![other]))
+					}
+				}
+			}
+			_ => {
+				return Err(err())
+			}
+		}
+	}
+	handlers.reverse();
+	let body = param_entries.collect();
+	in_force(&HANDLERS, handlers, || evaluate_body(&env, body).map_err(|e| signal(&env, e)))
+}
+
+//Parses (name (args...) body...) into the restart's name and a closure running it
+fn restart_clause(env: &Rc<RefCell<ExecutionEnv>>, clause: Literal) -> Result<(String, Executable), LispError> {
+	match clause {
+		Literal::List(l, _) if l.len() >= 2 && matches!(l[0], Literal::Atom(_, _)) => {
+			let mut parts = l.into_iter();
+			let name = parts.next().unwrap().print();
+			let args = Params { name: name.clone(), ..Params::parse(&parts.next().unwrap(), "restart-case")? };
+			let body = Literal::List(std::iter::once(Literal::Atom(format!("progn"), None)).chain(parts).collect(), None);
+			Ok((name, Executable::LispClosure(args, body, env.clone())))
+		}
+		_ => {
+			Err(LispError::new(ErrorKind::InputError, format!("form 'restart-case' requires restarts like (name (args...) body...)")))
+		}
+	}
+}
+
+//(restart-case form (name (args...) body...)...)
+//While form runs, (invoke-restart 'name args...) abandons it and calls the
+//restart instead, whose value becomes that of the restart-case
+pub fn builtin_restart_case(env: Rc<RefCell<ExecutionEnv>>, params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.is_empty() {
+		return Err(LispError::new(ErrorKind::InputError, format!("form 'restart-case' requires a form and its restarts")))
+	}
+	let mut param_entries = params.into_iter();
+	let form = param_entries.next().unwrap();
+	let restarts = param_entries.map(|c| restart_clause(&env, c)).collect::<Result<Vec<(String, Executable)>, LispError>>()?;
+	let names = restarts.iter().map(|(name, _)| name.clone()).collect();
+	let result = in_force(&RESTARTS, names, || ExecutionEnv::evaluate(env.clone(), form).map_err(|e| signal(&env, e)));
+	match result {
+		Err(e) => {
+			let restart = e.restart.as_ref().and_then(|(name, args)| {
+				restarts.iter().find(|(n, _)| n == name).map(|(_, exec)| (exec, args.clone()))
+			});
+			match restart {
+				Some((exec, args)) => {
+					exec.apply(env.clone(), args)
+				}
+				None => {
+					Err(e)
+				}
+			}
+		}
+		ok => {
+			ok
+		}
+	}
+}
+
+pub fn builtin_invoke_restart(params: Vec<Literal>) -> Result<Literal, LispError> {
+	let mut vals = params.into_iter();
+	let name = match vals.next() {
+		Some(Literal::Atom(name, _)) => {
+			name
+		}
+		_ => {
+			return Err(LispError::new(ErrorKind::InputError, format!("Operation 'invoke-restart' requires a restart name")))
+		}
+	};
+	if !RESTARTS.with(|r| r.borrow().contains(&name)) {
+		return Err(LispError::new(ErrorKind::InputError, format!("no restart named {} is in force", name)))
+	}
+	//Unwinds like an error until it reaches the restart-case
+	let mut err = LispError::new(ErrorKind::InputError, format!("restart '{}' invoked outside its restart-case", name));
+	err.restart = Some((name, vals.collect()));
+	Err(err)
+}
+
+//(raise value) raises any value, which catch hands back unchanged,
+//and raising a caught error raises it again as it was
+pub fn builtin_raise(params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation 'raise' requires 1 argument")))
+	}
+	match params.into_iter().next().unwrap() {
+		Literal::Err(e) => {
+			let mut err = e.as_ref().clone();
+			err.signaled = false;
+			Err(err)
+		}
+		payload => {
+			Err(LispError::raised(payload))
+		}
+	}
+}
+
+//(error message irritant...) raises a user error about the irritants
+pub fn builtin_error(params: Vec<Literal>) -> Result<Literal, LispError> {
+	let mut vals = params.into_iter();
+	match vals.next() {
+		Some(message) => {
+			Err(LispError::about(ErrorKind::UserError, message.print(), vals.collect()))
+		}
+		None => {
+			Err(LispError::new(ErrorKind::InputError, format!("Operation 'error' requires a message")))
+		}
+	}
+}
+
+pub fn builtin_is_error(params: Vec<Literal>) -> Result<Literal, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation 'error?' requires 1 argument")))
+	}
+	Ok(Literal::Bool(matches!(params[0], Literal::Err(_))))
+}
+
+//The single argument of an error accessor
+fn error_arg(params: Vec<Literal>, name: &str) -> Result<Rc<LispError>, LispError> {
+	if params.len() != 1 {
+		return Err(LispError::new(ErrorKind::InputError, format!("Operation '{}' requires 1 argument", name)))
+	}
+	match params.into_iter().next().unwrap() {
+		Literal::Err(e) => {
+			Ok(e)
+		}
+		other => {
+			Err(LispError::about(ErrorKind::TypeError, format!("Operation '{}' requires an error", name), vec![other]))
+		}
+	}
+}
+
+pub fn builtin_error_kind(params: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(Literal::Atom(error_arg(params, "error-kind")?.kind.name().to_string(), None))
+}
+
+pub fn builtin_error_message(params: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(Literal::String(error_arg(params, "error-message")?.message.clone()))
+}
+
+pub fn builtin_error_code(params: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(Literal::Num(Number::Fixnum(error_arg(params, "error-code")?.code())))
+}
+
+pub fn builtin_error_irritants(params: Vec<Literal>) -> Result<Literal, LispError> {
+	Ok(Literal::List(error_arg(params, "error-irritants")?.irritants.clone(), None))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::env::tests::{assert_error, assert_value, error_at, run};
+	use std::panic::{self, AssertUnwindSafe};
+
+	#[test]
+	fn panics_leave_no_restarts_in_force() {
+		let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+			in_force(&RESTARTS, vec![format!("skip")], || panic!("bug"))
+		}));
+		assert!(panicked.is_err());
+		assert!(RESTARTS.with(|r| r.borrow().is_empty()));
+	}
+
+	#[test]
+	fn defn_arity_errors_name_defn() {
+		assert_error("(defn f (x))", ErrorKind::InputError, "form 'defn' requires 3 arguments");
+	}
+
+	#[test]
+	fn conditionals_choose_a_branch() {
+		assert_value("(list (if true 1 2) (if false 1 2) (if nil 1) (if 0 1 2))", "(1 2 nil 1)");
+		assert_value("(cond ((= 1 2) 1) ((= 1 1) 2) (else 3))", "2");
+		assert_value("(cond ((= 1 2) 1))", "nil");
+		assert_value("(list (when true 1 2) (when false 1) (unless false 3) (unless true 3))", "(2 nil 3 nil)");
+		assert_error("(if)", ErrorKind::InputError, "form 'if' requires 2 or 3 arguments");
+	}
+
+	#[test]
+	fn let_forms_bind_in_a_new_scope() {
+		assert_value("(define x 1) (let ((x 2) (y x)) (list x y))", "(2 1)");
+		assert_value("(let* ((x 2) (y x)) (list x y))", "(2 2)");
+		assert_value("(letrec ((ev? (lambda (n) (if (= n 0) true (od? (- n 1)))))
+			(od? (lambda (n) (if (= n 0) false (ev? (- n 1))))))
+			(ev? 10))", "true");
+		assert_error("(define y (let ((x 2)) x)) x", ErrorKind::UnboundSymbol, "x is not a bound symbol");
+		assert_error("(let ((x)) x)", ErrorKind::InputError, "form 'let' requires a list of (name value) bindings");
+	}
+
+	#[test]
+	fn set_changes_the_nearest_binding() {
+		assert_value("(define x 1) (let ((x 2)) (set! x 3)) x", "1");
+		assert_value("(define x 1) (let ((y 2)) (set! x 3)) x", "3");
+		assert_value("(define x 1) (define x 2) x", "2");
+	}
+
+	#[test]
+	fn quote_and_short_circuits() {
+		assert_value("(list (quote (a b)) 'c)", "((a b) c)");
+		assert_value("(list (and) (and 1 2) (and 1 false (car 1)) (or) (or false 2) (or 1 (car 1)))", "(true 2 false false 2 1)");
+	}
+
+	//nil, false and the empty list are the only false values
+	#[test]
+	fn booleans_and_truthiness() {
+		assert_value("(list true false nil 'true)", "(true false nil true)");
+		assert_value("(list (not nil) (not false) (not 0) (not (list)) (if \"\" 1 2) (if (list) 1 2))", "(true true false true 1 2)");
+	}
+
+	#[test]
+	fn comparisons_give_booleans() {
+		assert_value("(list (= 1 1) (= 1 2) (< 1 2) (> 1 2) (<= 2 2) (>= 1 2))", "(true false true false true false)");
+		assert_value("(list (< 1 2 3) (< 1 3 2) (= 1 1.0))", "(true false true)");
+		assert_value("(list (eq? 'a 'a) (eq? 1 1) (eq? (list 1) (list 1)) (equal? (list 1) (list 1)) (equal? \"a\" \"a\"))", "(true true false true true)");
+		assert_error("(< 1 \"a\")", ErrorKind::TypeError, "Comparison '<' between non-numeric types not supported");
+	}
+
+	#[test]
+	fn macros_expand_before_evaluation() {
+		let unless = "(defmacro my-unless (c body) `(if ,c nil ,body))";
+		assert_value(&format!("{} (list (my-unless false 1) (my-unless true (car 1)))", unless), "(1 nil)");
+		assert_value(&format!("{} (macroexpand-1 '(my-unless x y))", unless), "(if x nil y)");
+		let thread = "(defmacro -> (x &rest fs) (if (not fs) x `(-> (,(car (car fs)) ,x ,@(cdr (car fs))) ,@(cdr fs))))";
+		assert_value(&format!("{} (list (-> 5 (- 1) (* 2)) (macroexpand-1 '(-> 5 (- 1) (* 2))))", thread), "(8 (-> (- 5 1) (* 2)))");
+	}
+
+	#[test]
+	fn macroexpand_repeats_until_no_macro_is_left() {
+		assert_value("(defmacro a (x) `(b ,x)) (defmacro b (x) `(+ ,x 1))
+			(list (macroexpand-1 '(a 1)) (macroexpand '(a 1)) (macroexpand '(+ 1 2)))", "((b 1) (+ 1 1) (+ 1 2))");
+		assert_error("(defmacro m (x) x) (m)", ErrorKind::ArityError, "m requires 1 argument but got 0");
+		assert_error("(defmacro m)", ErrorKind::InputError, "form 'defmacro' requires 3 arguments");
+	}
+
+	//The first error ends evaluation and reaches the caller unchanged
+	#[test]
+	fn errors_abort_evaluation() {
+		let car = "Operation 'car' requires a list";
+		assert_error("(+ 1 (car 1))", ErrorKind::TypeError, car);
+		assert_error("(list 1 (car 2) (undefined))", ErrorKind::TypeError, car);
+		assert_error("(if (car 1) 1 2)", ErrorKind::TypeError, car);
+		assert_value("(define x 0) (try (progn (set! x 1) (car 1) (set! x 2)) (catch (e) x))", "1");
+		assert_value("(define x 0) (defn f () (progn (car 1) (set! x 5))) (try (f) (catch (e) x))", "0");
+	}
+
+	#[test]
+	fn try_catches_by_kind_and_always_finishes() {
+		assert_value("(define log (list)) (list (try (car 1) (catch (e) (error-kind e)) (finally (set! log (cons 1 log)))) log)", "(type-error (1))");
+		assert_value("(list (try 1 (finally 2)) (try (car 1) (catch arithmetic-error (e) 1) (catch type-error (e) 2)))", "(1 2)");
+		assert_value("(define x 0) (try (try (car 1) (finally (set! x 1))) (catch (e) x))", "1");
+		assert_error("(try (car 1) (catch arithmetic-error (e) 1))", ErrorKind::TypeError, "Operation 'car' requires a list");
+		assert_error("(try 1 (finally (car 1)))", ErrorKind::TypeError, "Operation 'car' requires a list");
+	}
+
+	#[test]
+	fn raised_values_and_error_accessors() {
+		assert_value("(list (try (raise 'oops) (catch (e) e)) (try (raise {:code 7}) (catch (e) (get e :code))))", "(oops 7)");
+		assert_value("(define e (try (error \"bad\" 1 2) (catch (e) e)))
+			(list (error? e) (error-kind e) (error-message e) (error-code e) (error-irritants e))", "(true user-error \"bad\" -11 (1 2))");
+		assert_error("(raise 5)", ErrorKind::UserError, "uncaught raise");
+	}
+
+	#[test]
+	fn handlers_run_before_unwinding_and_can_restart() {
+		assert_value("(define seen nil) (try (handler-bind ((type-error (lambda (c) (set! seen (error-kind c))))) (car 1)) (catch (e) seen))", "type-error");
+		assert_value("(defn parse (s) (restart-case (error \"bad\" s) (use-value (v) v)))
+			(handler-bind ((user-error (lambda (c) (invoke-restart 'use-value 42)))) (parse 1))", "42");
+		assert_value("(restart-case (+ 1 (invoke-restart 'skip 2)) (skip (v) (* v 10)))", "20");
+		assert_error("(invoke-restart 'nope)", ErrorKind::InputError, "no restart named nope is in force");
+	}
+
+	#[test]
+	fn splicing_outside_a_list_is_an_error() {
+		assert_eq!(run("(define x '(1 2)) `,@x").expect_err("splice outside a list").kind, ErrorKind::SyntaxError);
+		assert_eq!(error_at("(define x (quote (1 2)))\n`,@x"), (2, 2));
+		assert_value("(define x '(1 2)) `(0 ,@x `,@x)", "(0 1 2 (quasiquote (unquote-splicing x)))");
+	}
+}
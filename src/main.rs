@@ -1,27 +1,302 @@
+//Strings are built with format! throughout, whether or not they interpolate,
+//so a literal passed to format! is the house style rather than a mistake
+#![allow(clippy::useless_format)]
+
 mod lex;
-use lex::Lexer;
 mod reader;
+mod binlisp;
+mod env;
+mod error;
+mod expand;
+mod liblisp;
+mod num;
+use lex::{Lexer, SyntaxError};
 use reader::Parser;
 use std::rc::Rc;
 use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+use std::io::{self, BufRead, Read, Write};
+use std::process;
+use std::thread;
+use std::panic::{self, AssertUnwindSafe};
 use crate::{env::ExecutionEnv, liblisp::Literal};
-mod env;
-mod liblisp;
+use error::{ErrorKind, LispError, ERROR_KINDS};
+
+
+const USAGE: &str = "Usage: ferrolisp [FILE | -e EXPR | --stdin | compile FILE [-o OUT]]
+
+  (no arguments)   start an interactive REPL
+  FILE             evaluate the forms in FILE, or run it if it is compiled
+  compile FILE     compile FILE to bytecode, written to OUT or FILE.flc
+  -e EXPR          evaluate EXPR and print the result
+  -, --stdin       evaluate a script read from standard input
+  -h, --help       print this message
+
+compile and (compile f) translate calls, macros, quote, progn, if, when,
+unless, cond, and, or, let, let*, letrec, lambda and set!, with defn and
+define at top level. Closures with &key parameters and forms like try,
+quasiquote or a nested defn can't be compiled: (compile f) gives a
+Compile Error for them, and compile keeps such top level forms as
+source, interpreted when the module runs.";
 
+//The interpreter runs on a thread of its own so programs can recurse deeply.
+//Only the pages a program reaches are ever touched
+pub const STACK_SIZE: usize = 512 << 20;
+//Half the stack, leaving plenty for whatever one call uses past the check
+pub const STACK_BUDGET: usize = STACK_SIZE / 2;
 
 fn main() {
-  let mut lexer: Lexer = Lexer::from_string("(defn add-3 (a) (+ a 3)) (add-3 3)".to_string());
-  lexer.tokenize();
+  let interpreter = thread::Builder::new().stack_size(STACK_SIZE).spawn(interpret);
+  match interpreter.map(|handle| handle.join()) {
+    Ok(Ok(())) => {}
+    Ok(Err(_)) => {
+      //The panic has already been reported
+      process::exit(101);
+    }
+    Err(e) => {
+      eprintln!("Error starting the interpreter: {}", e);
+      process::exit(1);
+    }
+  }
+}
+
+fn interpret() {
+  env::set_stack_budget(STACK_BUDGET);
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  let env = Rc::new(RefCell::new(ExecutionEnv::root()));
+  match args.iter().map(|a| a.as_str()).collect::<Vec<&str>>().as_slice() {
+    [] => {
+      repl(env);
+    }
+    ["-h"] | ["--help"] => {
+      println!("{}", USAGE);
+      println!("\nA script that fails, or whose last value is an error, exits with\nthe status of its error's kind:\n");
+      for kind in ERROR_KINDS {
+        println!("  {:<4} {}", exit_code(kind.code()), kind.title());
+      }
+    }
+    ["-e", expr] => {
+      let result = run_source(env, expr.to_string());
+      match &result {
+        Ok(Literal::Err(_)) | Err(_) => {}
+        Ok(value) => {
+          println!("{}", value.write());
+        }
+      }
+      exit_with(result);
+    }
+    ["-"] | ["--stdin"] => {
+      let mut source = String::new();
+      if let Err(e) = io::stdin().read_to_string(&mut source) {
+        eprintln!("Error reading standard input: {}", e);
+        process::exit(1);
+      }
+      exit_with(run_source(env, source));
+    }
+    ["compile", input] => {
+      let output = Path::new(input).with_extension("flc");
+      exit_with(compile_file(env, input, &output.to_string_lossy()));
+    }
+    ["compile", input, "-o", output] => {
+      exit_with(compile_file(env, input, output));
+    }
+    [path] if !path.starts_with('-') => {
+      let bytes = match fs::read(path) {
+        Ok(bytes) => {
+          bytes
+        }
+        Err(e) => {
+          eprintln!("Error reading {}: {}", path, e);
+          process::exit(1);
+        }
+      };
+      if binlisp::is_module(&bytes) {
+        match binlisp::read_module(&bytes) {
+          Ok(functions) => {
+            exit_with(binlisp::run_module(&functions, &env));
+          }
+          Err(e) => {
+            eprintln!("Error loading {}: {}", path, e);
+            process::exit(1);
+          }
+        }
+      }
+      match String::from_utf8(bytes) {
+        Ok(source) => {
+          exit_with(run_source(env, source));
+        }
+        Err(_) => {
+          eprintln!("Error reading {}: not valid UTF-8", path);
+          process::exit(1);
+        }
+      }
+    }
+    _ => {
+      eprintln!("{}", USAGE);
+      process::exit(2);
+    }
+  }
+}
+
+//Evaluates the forms in a script and returns the value of the last one,
+//an error in any of them ends the script
+fn run_source(env: Rc<RefCell<ExecutionEnv>>, source: String) -> Result<Literal, LispError> {
+  let mut lexer = Lexer::from_string(source);
+  lexer.tokenize()?;
+  eval_lexed(env, lexer, |_| {})
+}
+
+//Compiles every form of a script into a module written to output
+fn compile_file(env: Rc<RefCell<ExecutionEnv>>, input: &str, output: &str) -> Result<Literal, LispError> {
+  let source = fs::read_to_string(input).map_err(|e| {
+    LispError::new(ErrorKind::IoError, format!("can't read {}: {}", input, e))
+  })?;
+  let mut lexer = Lexer::from_string(source);
+  lexer.tokenize()?;
+  let mut functions = Vec::new();
+  for form in parse_lexed(lexer)? {
+    let form = expand::expand(&env, form)?;
+    //Macros are defined as soon as they're compiled so later forms can use them
+    if let Literal::List(l, _) = &form {
+      if matches!(l.first(), Some(Literal::Atom(head, _)) if head == "defmacro" || head == "define-syntax") {
+        ExecutionEnv::evaluate(env.clone(), form.clone())?;
+      }
+    }
+    functions.push(binlisp::compile_toplevel(&form, &env));
+  }
+  let bytes = binlisp::write_module(&functions).map_err(|e| LispError::new(ErrorKind::CompileError, e))?;
+  fs::write(output, bytes).map_err(|e| {
+    LispError::new(ErrorKind::IoError, format!("can't write {}: {}", output, e))
+  })?;
+  Ok(Literal::Nil)
+}
+
+//Terminates the process, reporting a top level error on stderr
+//and deriving the exit status from its code. A script whose last
+//value is an error, like one a try caught and returned, fails too
+fn exit_with(result: Result<Literal, LispError>) -> ! {
+  match result {
+    Err(e) => {
+      report(&e);
+      process::exit(exit_code(e.code()));
+    }
+    Ok(Literal::Err(e)) => {
+      report(&e);
+      process::exit(exit_code(e.code()));
+    }
+    Ok(_) => {
+      process::exit(0);
+    }
+  }
+}
+
+//Prints an error followed by the closures it was raised in, innermost first.
+//A closure that recursed is listed once with how many calls it left
+fn report(e: &LispError) {
+  eprintln!("{}", e);
+  let mut names = e.stack.iter().peekable();
+  while let Some(name) = names.next() {
+    let mut calls = 1;
+    while names.next_if_eq(&name).is_some() {
+      calls += 1;
+    }
+    if calls == 1 {
+      eprintln!("  in {}", name);
+    }
+    else {
+      eprintln!("  in {} ({} calls)", name, calls);
+    }
+  }
+}
+
+//Error codes may be negative or exceed what a process status can hold,
+//so fold them into 1..=255, never reporting an error as success
+fn exit_code(code: i64) -> i32 {
+  match (code.unsigned_abs() % 256) as i32 {
+    0 => 1,
+    c => c,
+  }
+}
+
+//Reads forms from stdin until EOF, evaluating each complete input in
+//the same environment so definitions persist between entries
+fn repl(env: Rc<RefCell<ExecutionEnv>>) {
+  let stdin = io::stdin();
+  let mut input = stdin.lock();
+  let mut buffer = String::new();
+  loop {
+    if buffer.is_empty() {
+      print!("ferrolisp> ");
+    }
+    else {
+      print!("... ");
+    }
+    io::stdout().flush().unwrap();
+    let mut line = String::new();
+    match input.read_line(&mut line) {
+      Ok(0) => {
+        println!();
+        return;
+      }
+      Ok(_) => {
+        buffer.push_str(&line);
+      }
+      Err(e) => {
+        eprintln!("Error reading input: {}", e);
+        return;
+      }
+    }
+    if buffer.trim().is_empty() {
+      buffer.clear();
+      continue;
+    }
+    let mut lexer = Lexer::from_string(buffer.clone());
+    let result = match lexer.tokenize_partial() {
+      Err(e) => {
+        Err(e.into())
+      }
+      Ok(_) if !lexer.is_complete() => {
+        continue;
+      }
+      Ok(_) => {
+        //A panic is a bug in ferrolisp. The default hook has reported it
+        //by the time it is caught here, so it only ends this entry
+        let evaluated = panic::catch_unwind(AssertUnwindSafe(|| {
+          eval_lexed(env.clone(), lexer, |v| println!("{}", v.write()))
+        }));
+        match evaluated {
+          Ok(result) => {
+            result
+          }
+          Err(_) => {
+            eprintln!("Internal error: the entry was abandoned, definitions it made may be incomplete");
+            Ok(Literal::Nil)
+          }
+        }
+      }
+    };
+    if let Err(e) = result {
+      report(&e);
+    }
+    buffer.clear();
+  }
+}
+
+fn parse_lexed(mut lexer: Lexer) -> Result<Vec<Literal>, SyntaxError> {
   lexer.substitute();
   let mut parser = Parser::from_tokenizer(lexer);
-  parser.parse();
-  let env = Rc::new(RefCell::new(ExecutionEnv::root()));
-  let returns: Vec<Literal> = parser.out().into_iter().map(|a| ExecutionEnv::evaluate(env.clone(), a)).collect();
-  print_literals(returns);
+  parser.parse()?;
+  Ok(parser.out())
 }
 
-fn print_literals(inp: Vec<Literal>) -> () {
-  for i in inp {
-    println!("{}",i);
+//Expands and evaluates each form in turn, passing its value to done,
+//and returns the last value or the first error
+fn eval_lexed(env: Rc<RefCell<ExecutionEnv>>, lexer: Lexer, done: fn(&Literal)) -> Result<Literal, LispError> {
+  let mut last = Literal::Nil;
+  for form in parse_lexed(lexer)? {
+    last = ExecutionEnv::evaluate(env.clone(), expand::expand(&env, form)?)?;
+    done(&last);
   }
-}
\ No newline at end of file
+  Ok(last)
+}
@@ -0,0 +1,667 @@
+use std::cmp::Ordering;
+
+//An integer of any size, as a sign and a magnitude in base 2^32 digits,
+//least significant first. There are never leading zero digits, so zero
+//has no digits, and zero is never negative
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BigInt {
+	negative: bool,
+	digits: Vec<u32>,
+}
+
+fn trim(mut digits: Vec<u32>) -> Vec<u32> {
+	while digits.last() == Some(&0) {
+		digits.pop();
+	}
+	digits
+}
+
+fn cmp_mag(a: &[u32], b: &[u32]) -> Ordering {
+	if a.len() != b.len() {
+		return a.len().cmp(&b.len());
+	}
+	for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+		if x != y {
+			return x.cmp(y);
+		}
+	}
+	Ordering::Equal
+}
+
+fn add_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+	let mut out = Vec::with_capacity(a.len().max(b.len()) + 1);
+	let mut carry = 0u64;
+	for i in 0..a.len().max(b.len()) {
+		let sum = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+		out.push(sum as u32);
+		carry = sum >> 32;
+	}
+	if carry > 0 {
+		out.push(carry as u32);
+	}
+	out
+}
+
+//a - b, where a is at least b
+fn sub_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+	let mut out = Vec::with_capacity(a.len());
+	let mut borrow = 0i64;
+	for (i, x) in a.iter().enumerate() {
+		let mut diff = *x as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+		borrow = 0;
+		if diff < 0 {
+			diff += 1 << 32;
+			borrow = 1;
+		}
+		out.push(diff as u32);
+	}
+	trim(out)
+}
+
+fn mul_mag(a: &[u32], b: &[u32]) -> Vec<u32> {
+	let mut out = vec![0u32; a.len() + b.len()];
+	for (i, x) in a.iter().enumerate() {
+		let mut carry = 0u64;
+		for (j, y) in b.iter().enumerate() {
+			let cur = out[i + j] as u64 + *x as u64 * *y as u64 + carry;
+			out[i + j] = cur as u32;
+			carry = cur >> 32;
+		}
+		let mut k = i + b.len();
+		while carry > 0 {
+			let cur = out[k] as u64 + carry;
+			out[k] = cur as u32;
+			carry = cur >> 32;
+			k += 1;
+		}
+	}
+	trim(out)
+}
+
+//a * m + add, for building numbers up a digit at a time
+fn mul_small(a: &[u32], m: u32, add: u32) -> Vec<u32> {
+	let mut out = Vec::with_capacity(a.len() + 1);
+	let mut carry = add as u64;
+	for x in a {
+		let cur = *x as u64 * m as u64 + carry;
+		out.push(cur as u32);
+		carry = cur >> 32;
+	}
+	if carry > 0 {
+		out.push(carry as u32);
+	}
+	trim(out)
+}
+
+fn divmod_small(a: &[u32], d: u32) -> (Vec<u32>, u32) {
+	let mut q = vec![0u32; a.len()];
+	let mut r = 0u64;
+	for i in (0..a.len()).rev() {
+		let cur = (r << 32) | a[i] as u64;
+		q[i] = (cur / d as u64) as u32;
+		r = cur % d as u64;
+	}
+	(trim(q), r as u32)
+}
+
+//Long division a bit at a time, b must not be zero
+fn divmod_mag(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+	if cmp_mag(a, b) == Ordering::Less {
+		return (Vec::new(), a.to_vec());
+	}
+	if b.len() == 1 {
+		let (q, r) = divmod_small(a, b[0]);
+		return (q, trim(vec![r]));
+	}
+	let mut q = vec![0u32; a.len()];
+	let mut r: Vec<u32> = Vec::new();
+	for i in (0..a.len() * 32).rev() {
+		let bit = (a[i / 32] >> (i % 32)) & 1;
+		r = mul_small(&r, 2, bit);
+		if cmp_mag(&r, b) != Ordering::Less {
+			r = sub_mag(&r, b);
+			q[i / 32] |= 1 << (i % 32);
+		}
+	}
+	(trim(q), r)
+}
+
+impl BigInt {
+	fn new(negative: bool, digits: Vec<u32>) -> Self {
+		let digits = trim(digits);
+		Self {
+			negative: negative && !digits.is_empty(),
+			digits,
+		}
+	}
+	pub fn from_i64(n: i64) -> Self {
+		let m = n.unsigned_abs();
+		BigInt::new(n < 0, vec![m as u32, (m >> 32) as u32])
+	}
+	pub fn to_i64(&self) -> Option<i64> {
+		if self.digits.len() > 2 {
+			return None;
+		}
+		let m = self.digits.iter().rev().fold(0u64, |acc, d| (acc << 32) | *d as u64);
+		if self.negative {
+			if m <= 1 << 63 {
+				return Some((m as i64).wrapping_neg());
+			}
+		}
+		else if m <= i64::MAX as u64 {
+			return Some(m as i64);
+		}
+		None
+	}
+	pub fn to_f64(&self) -> f64 {
+		let m = self.digits.iter().rev().fold(0.0, |acc, d| acc * 4294967296.0 + *d as f64);
+		if self.negative { -m } else { m }
+	}
+	//2 to the power k
+	fn pow2(k: usize) -> Self {
+		let mut digits = vec![0u32; k / 32];
+		digits.push(1 << (k % 32));
+		BigInt::new(false, digits)
+	}
+	//Parses optionally signed digits in the given radix
+	pub fn parse(s: &str, radix: u32) -> Option<Self> {
+		let (negative, digits) = match s.strip_prefix('-') {
+			Some(rest) => {
+				(true, rest)
+			}
+			None => {
+				(false, s.strip_prefix('+').unwrap_or(s))
+			}
+		};
+		if digits.is_empty() {
+			return None;
+		}
+		let mut mag = Vec::new();
+		for c in digits.chars() {
+			mag = mul_small(&mag, radix, c.to_digit(radix)?);
+		}
+		Some(BigInt::new(negative, mag))
+	}
+	pub fn is_zero(&self) -> bool {
+		self.digits.is_empty()
+	}
+	pub fn is_negative(&self) -> bool {
+		self.negative
+	}
+	pub fn neg(&self) -> Self {
+		BigInt::new(!self.negative, self.digits.clone())
+	}
+	pub fn abs(&self) -> Self {
+		BigInt::new(false, self.digits.clone())
+	}
+	pub fn add(&self, other: &BigInt) -> Self {
+		if self.negative == other.negative {
+			return BigInt::new(self.negative, add_mag(&self.digits, &other.digits));
+		}
+		match cmp_mag(&self.digits, &other.digits) {
+			Ordering::Less => {
+				BigInt::new(other.negative, sub_mag(&other.digits, &self.digits))
+			}
+			_ => {
+				BigInt::new(self.negative, sub_mag(&self.digits, &other.digits))
+			}
+		}
+	}
+	pub fn sub(&self, other: &BigInt) -> Self {
+		self.add(&other.neg())
+	}
+	pub fn mul(&self, other: &BigInt) -> Self {
+		BigInt::new(self.negative != other.negative, mul_mag(&self.digits, &other.digits))
+	}
+	//Truncating division, the remainder takes the sign of the dividend.
+	//other must not be zero
+	pub fn divmod(&self, other: &BigInt) -> (Self, Self) {
+		let (q, r) = divmod_mag(&self.digits, &other.digits);
+		(BigInt::new(self.negative != other.negative, q), BigInt::new(self.negative, r))
+	}
+	pub fn gcd(&self, other: &BigInt) -> Self {
+		let mut a = self.abs();
+		let mut b = other.abs();
+		while !b.is_zero() {
+			let r = a.divmod(&b).1;
+			a = b;
+			b = r;
+		}
+		a
+	}
+}
+
+impl Ord for BigInt {
+	fn cmp(&self, other: &Self) -> Ordering {
+		match (self.negative, other.negative) {
+			(false, true) => {
+				Ordering::Greater
+			}
+			(true, false) => {
+				Ordering::Less
+			}
+			(false, false) => {
+				cmp_mag(&self.digits, &other.digits)
+			}
+			(true, true) => {
+				cmp_mag(&other.digits, &self.digits)
+			}
+		}
+	}
+}
+
+impl PartialOrd for BigInt {
+	fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl std::fmt::Display for BigInt {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		if self.is_zero() {
+			return write!(f, "0");
+		}
+		//Nine decimal digits at a time, least significant first
+		let mut chunks = Vec::new();
+		let mut mag = self.digits.clone();
+		while !mag.is_empty() {
+			let (q, r) = divmod_small(&mag, 1_000_000_000);
+			chunks.push(r);
+			mag = q;
+		}
+		let mut out = String::new();
+		if self.negative {
+			out.push('-');
+		}
+		out.push_str(&chunks.pop().unwrap().to_string());
+		for chunk in chunks.iter().rev() {
+			out.push_str(&format!("{:09}", chunk));
+		}
+		write!(f, "{}", out)
+	}
+}
+
+//The numeric tower. Results are always kept in the simplest exact form,
+//so an integer that fits is a Fixnum and a Rational is never whole.
+//Any operation involving a Float is inexact
+#[derive(Clone, Debug)]
+pub enum Number {
+	Fixnum(i64),
+	//Integers outside the range of a fixnum
+	Bignum(BigInt),
+	//In lowest terms, with a denominator greater than one
+	Rational(BigInt, BigInt),
+	Float(f64),
+}
+
+//Normalizes an exact integer
+fn integer(n: BigInt) -> Number {
+	match n.to_i64() {
+		Some(i) => {
+			Number::Fixnum(i)
+		}
+		None => {
+			Number::Bignum(n)
+		}
+	}
+}
+
+//Normalizes an exact fraction, d must not be zero
+fn ratio(n: BigInt, d: BigInt) -> Number {
+	let (n, d) = if d.is_negative() { (n.neg(), d.neg()) } else { (n, d) };
+	let g = n.gcd(&d);
+	let (n, d) = (n.divmod(&g).0, d.divmod(&g).0);
+	if d == BigInt::from_i64(1) {
+		integer(n)
+	}
+	else {
+		Number::Rational(n, d)
+	}
+}
+
+//Numerator and denominator of an exact number
+fn fraction(n: &Number) -> (BigInt, BigInt) {
+	match n {
+		Number::Fixnum(i) => {
+			(BigInt::from_i64(*i), BigInt::from_i64(1))
+		}
+		Number::Bignum(b) => {
+			(b.clone(), BigInt::from_i64(1))
+		}
+		Number::Rational(a, b) => {
+			(a.clone(), b.clone())
+		}
+		//Operations handle floats before asking for a fraction
+		Number::Float(f) => {
+			(BigInt::from_i64(*f as i64), BigInt::from_i64(1))
+		}
+	}
+}
+
+//Removes digit separators, each of which must sit between two digits
+fn strip_separators(s: &str, radix: u32) -> Option<String> {
+	let chars: Vec<char> = s.chars().collect();
+	for (i, c) in chars.iter().enumerate() {
+		if *c == '_' {
+			let before = i.checked_sub(1).and_then(|j| chars.get(j));
+			let after = chars.get(i + 1);
+			if !(before.is_some_and(|c| c.is_digit(radix)) && after.is_some_and(|c| c.is_digit(radix))) {
+				return None;
+			}
+		}
+	}
+	Some(chars.into_iter().filter(|c| *c != '_').collect())
+}
+
+//Whether s is an optionally signed decimal with a point, an exponent or both
+fn is_decimal(s: &str) -> bool {
+	let s = s.strip_prefix(['+', '-']).unwrap_or(s);
+	let (mantissa, exponent) = match s.find(['e', 'E']) {
+		Some(i) => {
+			(&s[..i], Some(&s[i + 1..]))
+		}
+		None => {
+			(s, None)
+		}
+	};
+	let (whole, fract) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+	let digits = |t: &str| t.chars().all(|c| c.is_ascii_digit());
+	if whole.is_empty() && fract.is_empty() || !digits(whole) || !digits(fract) {
+		return false;
+	}
+	match exponent {
+		Some(e) => {
+			let e = e.strip_prefix(['+', '-']).unwrap_or(e);
+			!e.is_empty() && digits(e)
+		}
+		None => {
+			true
+		}
+	}
+}
+
+type ExactOp = fn((BigInt, BigInt), (BigInt, BigInt)) -> Number;
+
+impl Number {
+	//Reads a numeric literal: an optionally signed integer, a fraction
+	//like 1/3 or a decimal like -1.5e-9, or an integer in another radix
+	//after #x, #o, #b or #d. Digits may be grouped with _ separators
+	pub fn parse(s: &str) -> Option<Number> {
+		let (radix, body) = match s.strip_prefix('#') {
+			Some(rest) => {
+				let mut chars = rest.chars();
+				let radix = match chars.next()?.to_ascii_lowercase() {
+					'x' => {
+						16
+					}
+					'o' => {
+						8
+					}
+					'b' => {
+						2
+					}
+					'd' => {
+						10
+					}
+					_ => {
+						return None;
+					}
+				};
+				(radix, chars.as_str())
+			}
+			None => {
+				(10, s)
+			}
+		};
+		let body = strip_separators(body, radix)?;
+		if radix != 10 {
+			return BigInt::parse(&body, radix).map(integer);
+		}
+		if let Some((n, d)) = body.split_once('/') {
+			if d.starts_with(['+', '-']) {
+				return None;
+			}
+			let n = BigInt::parse(n, 10)?;
+			let d = BigInt::parse(d, 10)?;
+			if d.is_zero() {
+				return None;
+			}
+			return Some(ratio(n, d));
+		}
+		if body.trim_start_matches(['+', '-']).chars().all(|c| c.is_ascii_digit()) {
+			return BigInt::parse(&body, 10).map(integer);
+		}
+		if is_decimal(&body) {
+			return body.parse::<f64>().ok().map(Number::Float);
+		}
+		None
+	}
+	pub fn is_exact(&self) -> bool {
+		!matches!(self, Number::Float(_))
+	}
+	pub fn is_integer(&self) -> bool {
+		match self {
+			Number::Fixnum(_) | Number::Bignum(_) => {
+				true
+			}
+			Number::Rational(_, _) => {
+				false
+			}
+			Number::Float(f) => {
+				f.is_finite() && f.fract() == 0.0
+			}
+		}
+	}
+	pub fn is_zero(&self) -> bool {
+		match self {
+			Number::Fixnum(i) => {
+				*i == 0
+			}
+			Number::Float(f) => {
+				*f == 0.0
+			}
+			_ => {
+				false
+			}
+		}
+	}
+	pub fn to_f64(&self) -> f64 {
+		match self {
+			Number::Fixnum(i) => {
+				*i as f64
+			}
+			Number::Bignum(b) => {
+				b.to_f64()
+			}
+			Number::Rational(a, b) => {
+				a.to_f64() / b.to_f64()
+			}
+			Number::Float(f) => {
+				*f
+			}
+		}
+	}
+	pub fn to_inexact(&self) -> Number {
+		Number::Float(self.to_f64())
+	}
+	//The exact value of a float, which has none if it is infinite or NaN
+	pub fn to_exact(&self) -> Option<Number> {
+		let f = match self {
+			Number::Float(f) => {
+				*f
+			}
+			exact => {
+				return Some(exact.clone())
+			}
+		};
+		if !f.is_finite() {
+			return None;
+		}
+		//A finite float is mantissa * 2^exponent
+		let bits = f.to_bits();
+		let biased = ((bits >> 52) & 0x7ff) as i64;
+		let fract = bits & ((1 << 52) - 1);
+		let (mantissa, exponent) = if biased == 0 { (fract, -1074) } else { (fract | 1 << 52, biased - 1075) };
+		let mut mantissa = BigInt::from_i64(mantissa as i64);
+		if bits >> 63 == 1 {
+			mantissa = mantissa.neg();
+		}
+		if exponent >= 0 {
+			Some(integer(mantissa.mul(&BigInt::pow2(exponent as usize))))
+		}
+		else {
+			Some(ratio(mantissa, BigInt::pow2((-exponent) as usize)))
+		}
+	}
+	pub fn numerator(&self) -> Option<Number> {
+		match self {
+			Number::Float(_) => {
+				self.to_exact().and_then(|n| n.numerator()).map(|n| n.to_inexact())
+			}
+			_ => {
+				Some(integer(fraction(self).0))
+			}
+		}
+	}
+	pub fn denominator(&self) -> Option<Number> {
+		match self {
+			Number::Float(_) => {
+				self.to_exact().and_then(|n| n.denominator()).map(|n| n.to_inexact())
+			}
+			_ => {
+				Some(integer(fraction(self).1))
+			}
+		}
+	}
+	//Applies an operation at the lowest level of the tower both operands
+	//reach, falling back from fixnums to exact fractions on overflow
+	fn promote(&self, other: &Number, fixnum: fn(i64, i64) -> Option<i64>, exact: ExactOp, float: fn(f64, f64) -> f64) -> Number {
+		match (self, other) {
+			(Number::Float(_), _) | (_, Number::Float(_)) => {
+				Number::Float(float(self.to_f64(), other.to_f64()))
+			}
+			(Number::Fixnum(a), Number::Fixnum(b)) => {
+				match fixnum(*a, *b) {
+					Some(n) => {
+						Number::Fixnum(n)
+					}
+					None => {
+						exact(fraction(self), fraction(other))
+					}
+				}
+			}
+			_ => {
+				exact(fraction(self), fraction(other))
+			}
+		}
+	}
+	pub fn add(&self, other: &Number) -> Number {
+		self.promote(other, i64::checked_add, |(an, ad), (bn, bd)| ratio(an.mul(&bd).add(&bn.mul(&ad)), ad.mul(&bd)), |a, b| a + b)
+	}
+	pub fn sub(&self, other: &Number) -> Number {
+		self.promote(other, i64::checked_sub, |(an, ad), (bn, bd)| ratio(an.mul(&bd).sub(&bn.mul(&ad)), ad.mul(&bd)), |a, b| a - b)
+	}
+	pub fn mul(&self, other: &Number) -> Number {
+		self.promote(other, i64::checked_mul, |(an, ad), (bn, bd)| ratio(an.mul(&bn), ad.mul(&bd)), |a, b| a * b)
+	}
+	//Exact division by zero is an error, inexact gives an infinity or NaN
+	pub fn div(&self, other: &Number) -> Result<Number, String> {
+		if other.is_exact() && other.is_zero() && self.is_exact() {
+			return Err(format!("division by zero"));
+		}
+		//Fixnums that divide evenly stay fixnums
+		let exact_quotient = |a: i64, b: i64| if a.checked_rem(b) == Some(0) { a.checked_div(b) } else { None };
+		Ok(self.promote(other, exact_quotient, |(an, ad), (bn, bd)| ratio(an.mul(&bd), ad.mul(&bn)), |a, b| a / b))
+	}
+	//Integer division of integers, truncating towards zero, with the
+	//remainder taking the sign of the dividend
+	pub fn quotient(&self, other: &Number) -> Result<(Number, Number), String> {
+		if !self.is_integer() || !other.is_integer() {
+			return Err(format!("requires integers"));
+		}
+		if other.is_zero() {
+			return Err(format!("division by zero"));
+		}
+		match (self, other) {
+			(Number::Float(_), _) | (_, Number::Float(_)) => {
+				let (a, b) = (self.to_f64(), other.to_f64());
+				Ok((Number::Float((a / b).trunc()), Number::Float(a % b)))
+			}
+			(Number::Fixnum(a), Number::Fixnum(b)) if a.checked_div(*b).is_some() => {
+				Ok((Number::Fixnum(a / b), Number::Fixnum(a % b)))
+			}
+			_ => {
+				let (q, r) = fraction(self).0.divmod(&fraction(other).0);
+				Ok((integer(q), integer(r)))
+			}
+		}
+	}
+	//Numeric order, which doesn't exist when a NaN is involved
+	pub fn compare(&self, other: &Number) -> Option<Ordering> {
+		match (self, other) {
+			(Number::Float(_), _) | (_, Number::Float(_)) => {
+				self.to_f64().partial_cmp(&other.to_f64())
+			}
+			(Number::Fixnum(a), Number::Fixnum(b)) => {
+				Some(a.cmp(b))
+			}
+			_ => {
+				let (an, ad) = fraction(self);
+				let (bn, bd) = fraction(other);
+				Some(an.mul(&bd).cmp(&bn.mul(&ad)))
+			}
+		}
+	}
+	//The same number with the same exactness, used by eq?
+	pub fn is_eqv(&self, other: &Number) -> bool {
+		match (self, other) {
+			(Number::Float(a), Number::Float(b)) => {
+				a == b
+			}
+			(Number::Float(_), _) | (_, Number::Float(_)) => {
+				false
+			}
+			_ => {
+				self.compare(other) == Some(Ordering::Equal)
+			}
+		}
+	}
+	pub fn print(&self) -> String {
+		match self {
+			Number::Fixnum(i) => {
+				i.to_string()
+			}
+			Number::Bignum(b) => {
+				b.to_string()
+			}
+			Number::Rational(a, b) => {
+				format!("{}/{}", a, b)
+			}
+			Number::Float(f) => {
+				if f.is_nan() {
+					format!("+nan.0")
+				}
+				else if f.is_infinite() {
+					format!("{}inf.0", if *f > 0.0 { "+" } else { "-" })
+				}
+				else {
+					//Debug keeps the point on whole floats, so 1.0 doesn't read back exact
+					format!("{:?}", f)
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn overflowing_fixnum_division_promotes() {
+		let quotient = Number::Fixnum(i64::MIN).div(&Number::Fixnum(-1)).unwrap();
+		assert_eq!(quotient.print(), "9223372036854775808");
+		assert_eq!(Number::Fixnum(-9).div(&Number::Fixnum(3)).unwrap().print(), "-3");
+		assert_eq!(Number::Fixnum(1).div(&Number::Fixnum(3)).unwrap().print(), "1/3");
+	}
+}
@@ -1,4 +1,5 @@
 use crate::lex;
+use crate::lex::{Position, Span, SyntaxError};
 
 use crate::liblisp::Literal;
 
@@ -7,6 +8,7 @@ use crate::liblisp::Literal;
 pub struct Parser {
   pub stream: Vec<lex::Token>,
   pub out: Vec<Literal>,
+  spans: Vec<Span>,
   current_tok: lex::Token,
   index: usize,
   stream_length: usize,
@@ -18,99 +20,280 @@ impl Parser {
     Parser { 
       stream: cache.clone(), 
       out: Vec::new(), 
+      spans: to_parse.spans.clone(),
       current_tok: cache[0].clone(),
       index: 0,
-      stream_length: cache.into_iter().count(),
+      stream_length: cache.len(),
     }
   }
+  #[allow(clippy::needless_return)]
   pub fn out(self) -> Vec<Literal> {
     return self.out;
   }
-  pub fn parse(&mut self) {
+  pub fn parse(&mut self) -> Result<(), SyntaxError> {
     let mut ret;
     loop {
       if self.index >= self.stream_length {
         break;
       }
+      self.skip_datum_comments()?;
       match self.current_tok.clone() {
-        lex::Token::Bracket(_) => {
-          ret = self.parse_list();
-        }
         lex::Token::EndOfFile => {
-          return;
+          return Ok(());
         }
         _ => {
-          ret = self.parse_atomic();
+          ret = self.parse_datum()?;
         }
       }
       self.out.push(ret.clone());
     }
+    Ok(())
+  }
+  //Reads one complete form starting at the current token
+  fn parse_datum(&mut self) -> Result<Literal, SyntaxError> {
+    match self.current_tok.clone() {
+      lex::Token::Bracket(lex::Bracket::ParenOpen) => {
+        self.parse_list()
+      }
+      lex::Token::Bracket(lex::Bracket::BracketOpen) => {
+        let (items, span) = self.parse_sequence(lex::Bracket::BracketClose)?;
+        Ok(Literal::Vector(items, Some(span)))
+      }
+      lex::Token::Bracket(lex::Bracket::CurlyOpen) => {
+        let start = self.position();
+        let (items, span) = self.parse_sequence(lex::Bracket::CurlyClose)?;
+        if !items.len().is_multiple_of(2) {
+          return Err(SyntaxError::new(format!("Map literal has a key without a value"), start));
+        }
+        Ok(Literal::Map(Vec::new(), Some(span)).with_forms(items))
+      }
+      lex::Token::Bracket(lex::Bracket::HashCurlyOpen) => {
+        let (items, span) = self.parse_sequence(lex::Bracket::CurlyClose)?;
+        Ok(Literal::Set(items, Some(span)))
+      }
+      lex::Token::Quote(name) => {
+        self.parse_quoted(name)
+      }
+      _ => {
+        self.parse_atomic()
+      }
+    }
+  }
+  //'x reads as (quote x), and likewise for ` , and ,@
+  fn parse_quoted(&mut self, name: String) -> Result<Literal, SyntaxError> {
+    let start = self.position();
+    self.get_next_token();
+    self.skip_datum_comments()?;
+    if let lex::Token::EndOfFile = self.current_tok {
+      return Err(SyntaxError::new(format!("Expected a form after {}", name), start));
+    }
+    let quoted = self.parse_datum()?;
+    let end = self.spans[self.index.saturating_sub(1)].end;
+    Ok(Literal::List(vec![Literal::Atom(name, None), quoted], Some(Span::new(start, end))))
   }
-  fn parse_atomic(&mut self) -> Literal{
+  #[allow(clippy::needless_return)]
+  fn parse_atomic(&mut self) -> Result<Literal, SyntaxError> {
     match self.current_tok.clone() {
       lex::Token::Key(s) => {
         self.get_next_token();
-        return Literal::Atom(s);
+        return Ok(Literal::Keyword(s.trim_start_matches(':').to_string()));
       }
       lex::Token::Ident(s) => {
+        let span = self.span();
         self.get_next_token();
-        return Literal::Atom(s);
+        return Ok(Literal::Atom(s, Some(span)));
       }
       lex::Token::Number(i) => {
         self.get_next_token();
-        return Literal::Num(i);
+        return Ok(Literal::Num(i));
       }
       lex::Token::String(s) => {
         self.get_next_token();
-        return Literal::String(s);
+        return Ok(Literal::String(s));
       }
       lex::Token::Reserved(s) => {
         self.get_next_token();
-        return Literal::Atom(s);
+        match s.as_str() {
+          "true" => {
+            return Ok(Literal::Bool(true));
+          }
+          "false" => {
+            return Ok(Literal::Bool(false));
+          }
+          _ => {
+            return Ok(Literal::Nil);
+          }
+        }
       }
-      lex::Token::Bracket(_b) => {
-        self.get_next_token();
-        return Literal::Err("Parser Error: Unexpected bracket encountered".to_string(), -1)
+      lex::Token::Bracket(b) => {
+        return Err(SyntaxError::new(format!("Unexpected bracket {} encountered", b._format()), self.position()))
+      }
+      lex::Token::Quote(name) => {
+        return Err(SyntaxError::new(format!("Unexpected {} encountered", name), self.position()))
+      }
+      lex::Token::DatumComment => {
+        return Err(SyntaxError::new(format!("Unexpected #; encountered"), self.position()))
       }
       lex::Token::EndOfFile => {
-        self.get_next_token();
-        return Literal::Err("Parser Error: Unexpected EOF encountered".to_string(), -1)
+        return Err(SyntaxError::new(format!("Unexpected EOF encountered"), self.position()))
+      }
+    }
+  }
+  //Reads and drops the form after each #; at the current token
+  fn skip_datum_comments(&mut self) -> Result<(), SyntaxError> {
+    while let lex::Token::DatumComment = self.current_tok {
+      let start = self.position();
+      self.get_next_token();
+      //In #; #; a b both a and b are skipped
+      self.skip_datum_comments()?;
+      match self.current_tok {
+        lex::Token::EndOfFile | lex::Token::Bracket(lex::Bracket::ParenClose | lex::Bracket::BracketClose | lex::Bracket::CurlyClose) => {
+          return Err(SyntaxError::new(format!("Expected a form after #;"), start));
+        }
+        _ => {
+          self.parse_datum()?;
+        }
       }
     }
+    Ok(())
+  }
+  fn parse_list(&mut self) -> Result<Literal, SyntaxError> {
+    let (items, span) = self.parse_sequence(lex::Bracket::ParenClose)?;
+    Ok(Literal::List(items, Some(span)))
   }
-  fn parse_list(&mut self) -> Literal {
+  //Reads the forms between the opening bracket at the current token and
+  //the given closer, any other closing bracket is a mismatch
+  fn parse_sequence(&mut self, close: lex::Bracket) -> Result<(Vec<Literal>, Span), SyntaxError> {
     let mut ret = Vec::new();
+    let open = self.position();
+    let opener = match self.current_tok {
+      lex::Token::Bracket(b) => {
+        b._format()
+      }
+      _ => {
+        String::new()
+      }
+    };
     self.get_next_token();
     loop {
+      self.skip_datum_comments()?;
       match self.current_tok {
-        lex::Token::Bracket(b) => {
-          match b {
-            lex::Bracket::ParenOpen => {
-              ret.push(self.parse_list());
+        lex::Token::Bracket(b) if b == close => {
+          let span = Span::new(open, self.span().end);
+          self.get_next_token();
+          return Ok((ret, span));
+        }
+        lex::Token::Bracket(b @ (lex::Bracket::ParenClose | lex::Bracket::BracketClose | lex::Bracket::CurlyClose)) => {
+          return Err(SyntaxError::new(format!("Expected {} to close the {} at {} but found {}", close._format(), opener, open, b._format()), self.position()));
+        }
+        lex::Token::EndOfFile => {
+          let name = match close {
+            lex::Bracket::ParenClose => {
+              "Parenthesis"
+            }
+            lex::Bracket::BracketClose => {
+              "Bracket"
             }
             _ => {
-              self.get_next_token();
-              return Literal::List(ret);
+              "Brace"
             }
-          }
-        }
-        lex::Token::EndOfFile => {
-          ret.push(Literal::Err("Parser Error: Unmatched Parenthesis".to_string(), -1));
-          return Literal::List(ret);
+          };
+          return Err(SyntaxError::new(format!("Unmatched {}", name), open));
         }
         _ =>  {
-          ret.push(self.parse_atomic());
+          ret.push(self.parse_datum()?);
         }
       }
+    }
+  }
+  //Span of the current token in the source text
+  fn span(&self) -> Span {
+    match self.current_tok {
+      lex::Token::EndOfFile => {
+        *self.spans.last().unwrap()
+      }
+      _ => {
+        self.spans[self.index]
+      }
+    }
   }
+  fn position(&self) -> Position {
+    self.span().start
   }
   fn get_next_token(&mut self) {
-    if self.stream.clone().into_iter().count() == 0 || self.stream.clone().into_iter().count() == self.index+1 {
+    if self.stream.is_empty() || self.stream.len() == self.index+1 {
       self.current_tok = lex::Token::EndOfFile;
       return;
     }
-    self.index = self.index+1;
+    self.index += 1;
     self.current_tok = (self.stream[self.index]).clone();
   }
 }
 
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::env::tests::{assert_value, read};
+
+  fn parse(source: &str) -> Result<Vec<Literal>, SyntaxError> {
+    let mut lexer = lex::Lexer::from_string(source.to_string());
+    lexer.tokenize()?;
+    let mut parser = Parser::from_tokenizer(lexer);
+    parser.parse()?;
+    Ok(parser.out())
+  }
+
+  fn error_at(source: &str) -> (String, usize, usize, usize) {
+    let err = parse(source).unwrap_err();
+    (err.message, err.position.line, err.position.column, err.position.offset)
+  }
+
+  #[test]
+  fn unbalanced_brackets_are_located() {
+    assert_eq!(error_at("(+ 1 2)\n(f (g 1)"), (format!("Unmatched Parenthesis"), 2, 1, 8));
+    assert_eq!(error_at("(+ 1 2))"), (format!("Unexpected bracket ) encountered"), 1, 8, 7));
+    assert_eq!(error_at("'"), (format!("Expected a form after quote"), 1, 1, 0));
+    assert!(parse("(+ 1 2) ; done").is_ok());
+  }
+
+  #[test]
+  fn collection_literals() {
+    match &read("[1 a] {:k v} #{x}").unwrap()[..] {
+      [Literal::Vector(items, _), Literal::Map(pairs, _), Literal::Set(elements, _)] => {
+        assert_eq!((items.len(), pairs.len(), elements.len()), (2, 1, 1));
+      }
+      other => {
+        panic!("read {:?}", other);
+      }
+    }
+    assert_value("(list [1 2 (+ 1 2)] {:a (+ 1 1) \"b\" [3]} #{1 2} [] {} #{})", "([1 2 3] {:a 2 \"b\" [3]} #{1 2} [] {} #{})");
+    assert_value("'[a (b)]", "[a (b)]");
+  }
+
+  #[test]
+  fn mismatched_closers_are_errors() {
+    assert_eq!(error_at("(f]"), (format!("Expected ) to close the ( at line 1, column 1 but found ]"), 1, 3, 2));
+    assert_eq!(error_at("[1 2)").0, "Expected ] to close the [ at line 1, column 1 but found )");
+    assert_eq!(error_at("{:a 1]").0, "Expected } to close the { at line 1, column 1 but found ]");
+    assert_eq!(error_at("\n#{1 2]").0, "Expected } to close the #{ at line 2, column 1 but found ]");
+    assert_eq!(error_at("[{:a}]"), (format!("Map literal has a key without a value"), 1, 2, 1));
+  }
+
+  //Keys and elements are only compared once evaluated
+  #[test]
+  fn collections_keep_repeated_forms() {
+    match &read("{(f) 1 (f) 2} #{(f) (f)}").unwrap()[..] {
+      [Literal::Map(pairs, _), Literal::Set(items, _)] => {
+        assert_eq!((pairs.len(), items.len()), (2, 2));
+      }
+      other => {
+        panic!("read {:?}", other);
+      }
+    }
+    let counter = "(define n 0) (defn f () (set! n (+ n 1)))";
+    assert_value(&format!("{} {{(f) 1 (f) 2}}", counter), "{1 1 2 2}");
+    assert_value(&format!("{} #{{(f) (f)}}", counter), "#{1 2}");
+    assert_value(&format!("{} (defn g () (list {{(f) 1 (f) 2}} #{{(f) (f)}})) (set! g (compile g)) (g)", counter), "({1 1 2 2} #{3 4})");
+    assert_value("(list {:a 1 :a 2} #{1 1})", "({:a 2} #{1})");
+  }
+}
@@ -0,0 +1,74 @@
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+//Runs the ferrolisp binary, giving its exit status, standard output and standard error
+fn ferrolisp(args: &[&str]) -> (i32, String, String) {
+  ferrolisp_reading(args, "")
+}
+
+//Runs the ferrolisp binary with the given text on its standard input
+fn ferrolisp_reading(args: &[&str], input: &str) -> (i32, String, String) {
+  let mut child = Command::new(env!("CARGO_BIN_EXE_ferrolisp")).args(args)
+    .stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+  child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+  let output = child.wait_with_output().unwrap();
+  let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
+  (output.status.code().unwrap(), text(&output.stdout), text(&output.stderr))
+}
+
+#[test]
+fn values_exit_successfully() {
+  assert_eq!(ferrolisp(&["-e", "(+ 1 2)"]), (0, String::from("3\n"), String::new()));
+}
+
+#[test]
+fn errors_exit_with_their_kind() {
+  let (status, _, stderr) = ferrolisp(&["-e", "(car 1)"]);
+  assert_eq!(status, 4);
+  assert!(stderr.starts_with("Type Error"), "{}", stderr);
+}
+
+//Nothing after the first error in a script is evaluated
+#[test]
+fn scripts_stop_at_their_first_error() {
+  let script = std::env::temp_dir().join(format!("ferrolisp-cli-stop-{}.lisp", std::process::id()));
+  fs::write(&script, "(define x 4)\n(car x)\n(undefined-name)\n").unwrap();
+  let (status, stdout, stderr) = ferrolisp(&[script.to_str().unwrap()]);
+  fs::remove_file(&script).unwrap();
+  assert_eq!((status, stdout.as_str()), (4, ""));
+  assert!(stderr.starts_with("Type Error"), "{}", stderr);
+}
+
+#[test]
+fn scripts_can_be_read_from_standard_input() {
+  assert_eq!(ferrolisp_reading(&["--stdin"], "(define x 4)\n(* x x)\n").0, 0);
+  let (status, _, stderr) = ferrolisp_reading(&["-"], "(car 1)");
+  assert_eq!(status, 4);
+  assert!(stderr.starts_with("Type Error"), "{}", stderr);
+}
+
+#[test]
+fn unreadable_input_fails() {
+  let (status, _, stderr) = ferrolisp(&["-e", "(+ 1"]);
+  assert_eq!(status, 1);
+  assert!(stderr.starts_with("Parse Error: Unmatched Parenthesis at line 1, column 1"), "{}", stderr);
+  let (status, _, stderr) = ferrolisp(&["/nonexistent/script.lisp"]);
+  assert_eq!(status, 1);
+  assert!(stderr.starts_with("Error reading /nonexistent/script.lisp"), "{}", stderr);
+  assert_eq!(ferrolisp(&["-x"]).0, 2);
+}
+
+//A caught error returned as the last value still fails the script
+#[test]
+fn error_values_exit_with_their_kind() {
+  let (status, stdout, stderr) = ferrolisp(&["-e", "(try (error \"boom\") (catch (e) e))"]);
+  assert_eq!((status, stdout.as_str()), (11, ""));
+  assert!(stderr.contains("boom"), "{}", stderr);
+  let script = std::env::temp_dir().join(format!("ferrolisp-cli-{}.lisp", std::process::id()));
+  fs::write(&script, "(define caught (try (car 1) (catch (e) e)))\ncaught\n").unwrap();
+  let (status, _, stderr) = ferrolisp(&[script.to_str().unwrap()]);
+  fs::remove_file(&script).unwrap();
+  assert_eq!(status, 4);
+  assert!(stderr.starts_with("Type Error"), "{}", stderr);
+}